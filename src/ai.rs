@@ -0,0 +1,457 @@
+use chess_lib::board::pieces::{get_legal_moves, Color, Move, PieceType};
+use chess_lib::board::Board;
+use chess_networking::Ack;
+use ggez::GameResult;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fen::Fen;
+use crate::networking::{Message, ReadError, Takeback};
+use crate::opponent::{self, GameCopy, Opponent};
+use crate::rules::{self, CastlingRights};
+
+// Scores are in centipawns from the point of view of the side to move
+const MATE: i32 = 100_000;
+const INFINITY: i32 = 1_000_000;
+
+// Piece-square tables from white's side, first row is the eighth rank
+#[rustfmt::skip]
+const PAWN_TABLE: [[i32; 8]; 8] = [
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+    [ 50,  50,  50,  50,  50,  50,  50,  50],
+    [ 10,  10,  20,  30,  30,  20,  10,  10],
+    [  5,   5,  10,  25,  25,  10,   5,   5],
+    [  0,   0,   0,  20,  20,   0,   0,   0],
+    [  5,  -5, -10,   0,   0, -10,  -5,   5],
+    [  5,  10,  10, -20, -20,  10,  10,   5],
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+];
+#[rustfmt::skip]
+const KNIGHT_TABLE: [[i32; 8]; 8] = [
+    [-50, -40, -30, -30, -30, -30, -40, -50],
+    [-40, -20,   0,   0,   0,   0, -20, -40],
+    [-30,   0,  10,  15,  15,  10,   0, -30],
+    [-30,   5,  15,  20,  20,  15,   5, -30],
+    [-30,   0,  15,  20,  20,  15,   0, -30],
+    [-30,   5,  10,  15,  15,  10,   5, -30],
+    [-40, -20,   0,   5,   5,   0, -20, -40],
+    [-50, -40, -30, -30, -30, -30, -40, -50],
+];
+#[rustfmt::skip]
+const BISHOP_TABLE: [[i32; 8]; 8] = [
+    [-20, -10, -10, -10, -10, -10, -10, -20],
+    [-10,   0,   0,   0,   0,   0,   0, -10],
+    [-10,   0,   5,  10,  10,   5,   0, -10],
+    [-10,   5,   5,  10,  10,   5,   5, -10],
+    [-10,   0,  10,  10,  10,  10,   0, -10],
+    [-10,  10,  10,  10,  10,  10,  10, -10],
+    [-10,   5,   0,   0,   0,   0,   5, -10],
+    [-20, -10, -10, -10, -10, -10, -10, -20],
+];
+#[rustfmt::skip]
+const ROOK_TABLE: [[i32; 8]; 8] = [
+    [  0,   0,   0,   0,   0,   0,   0,   0],
+    [  5,  10,  10,  10,  10,  10,  10,   5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [ -5,   0,   0,   0,   0,   0,   0,  -5],
+    [  0,   0,   0,   5,   5,   0,   0,   0],
+];
+#[rustfmt::skip]
+const QUEEN_TABLE: [[i32; 8]; 8] = [
+    [-20, -10, -10,  -5,  -5, -10, -10, -20],
+    [-10,   0,   0,   0,   0,   0,   0, -10],
+    [-10,   0,   5,   5,   5,   5,   0, -10],
+    [ -5,   0,   5,   5,   5,   5,   0,  -5],
+    [  0,   0,   5,   5,   5,   5,   0,  -5],
+    [-10,   5,   5,   5,   5,   5,   0, -10],
+    [-10,   0,   5,   0,   0,   0,   0, -10],
+    [-20, -10, -10,  -5,  -5, -10, -10, -20],
+];
+#[rustfmt::skip]
+const KING_TABLE: [[i32; 8]; 8] = [
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-30, -40, -40, -50, -50, -40, -40, -30],
+    [-20, -30, -30, -40, -40, -30, -30, -20],
+    [-10, -20, -20, -20, -20, -20, -20, -10],
+    [ 20,  20,   0,   0,   0,   0,  20,  20],
+    [ 20,  30,  10,   0,   0,  10,  30,  20],
+];
+
+/// How hard the computer thinks about each move
+#[derive(Clone, Copy, Debug)]
+pub struct Level {
+    pub level: u8,
+    /// Deepest full search in plies
+    pub depth: u32,
+    /// Time after which the deepest finished search is played
+    pub movetime: Duration,
+}
+
+impl Level {
+    /// Strength from 1 (a one ply search) to 5
+    pub fn new(level: u8) -> Level {
+        let level = level.clamp(1, 5);
+        Level {
+            level,
+            depth: level as u32,
+            movetime: Duration::from_millis(250 << level),
+        }
+    }
+}
+
+/// What the search needs to know about a position. Boards are copied freely, so
+/// the search never touches the `Game` the moves are played on.
+#[derive(Clone, Copy)]
+struct Position {
+    board: Board,
+    turn: Color,
+    castling: CastlingRights,
+    en_passant: Option<Move>,
+}
+
+/// A move as (from x, from y, to)
+type SearchMove = (i32, i32, Move);
+
+/// Computer opponent playing from a copy of the game. It searches on a background
+/// thread so the window stays responsive, declines draw offers and never offers any.
+pub struct AiOpponent {
+    color: Color,
+    level: Level,
+    name: String,
+    copy: GameCopy,
+    // Packets waiting to be picked up by poll
+    outbox: VecDeque<Message>,
+    // Result of the search running on the background thread
+    thinking: Option<Receiver<Option<SearchMove>>>,
+}
+
+impl AiOpponent {
+    pub fn new(color: Color, level: Level, start: &Fen) -> AiOpponent {
+        let mut opponent = AiOpponent {
+            color,
+            level,
+            name: format!("Computer (level {})", level.level),
+            copy: GameCopy::new(start),
+            outbox: VecDeque::new(),
+            thinking: None,
+        };
+        if opponent.copy.game.turn == color {
+            opponent.think();
+        }
+        opponent
+    }
+
+    /// Starts searching the current position on a background thread
+    fn think(&mut self) {
+        let position = Position {
+            board: self.copy.game.board,
+            turn: self.copy.game.turn,
+            castling: self.copy.history.castling,
+            en_passant: self.copy.history.en_passant,
+        };
+        let level = self.level;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver is gone if the game ended in the meantime
+            let _ = sender.send(best_move(&position, level));
+        });
+        self.thinking = Some(receiver);
+    }
+
+    /// Plays the move the search came up with, or resigns without one
+    fn play(&mut self, best: Option<SearchMove>) {
+        let mov = best.and_then(|(from_x, from_y, to)| {
+            self.copy
+                .play((from_x as usize, from_y as usize).into(), to, None)
+        });
+        self.outbox
+            .push_back(Message::Move(mov.unwrap_or_else(opponent::forfeit)));
+    }
+}
+
+impl Opponent for AiOpponent {
+    fn local_color(&self) -> Option<Color> {
+        Some(rules::opponent(self.color))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn send_move(&mut self, mov: chess_networking::Move) -> GameResult {
+        if mov.forfeit {
+            self.outbox.push_back(Message::Ack(Ack {
+                ok: true,
+                end_state: None,
+            }));
+            return Ok(());
+        }
+        let ack = self.copy.ack(&mov);
+        if ack.ok && ack.end_state.is_none() {
+            self.think();
+        }
+        self.outbox.push_back(Message::Ack(ack));
+        Ok(())
+    }
+
+    fn send_ack(&mut self, ack: Ack) -> GameResult {
+        if !ack.ok {
+            // Our copy of the game no longer matches the board
+            self.outbox.push_back(Message::Move(opponent::forfeit()));
+        }
+        Ok(())
+    }
+
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult {
+        let Takeback::Request { moves } = takeback else {
+            return Ok(());
+        };
+        if !self.copy.take_back(moves as usize) {
+            self.outbox.push_back(Message::Takeback(Takeback::Decline));
+            return Ok(());
+        }
+        // Whatever the search comes up with is for a position that is gone
+        self.thinking = None;
+        self.outbox.push_back(Message::Takeback(Takeback::Accept));
+        if self.copy.game.turn == self.color {
+            self.think();
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        if let Some(receiver) = &self.thinking {
+            match receiver.try_recv() {
+                Ok(best) => {
+                    self.thinking = None;
+                    self.play(best);
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    self.thinking = None;
+                    self.outbox.push_back(Message::Move(opponent::forfeit()));
+                }
+            }
+        }
+        Ok(self.outbox.pop_front())
+    }
+}
+
+/// Searches deeper and deeper until `level.depth` or `level.movetime` is reached and
+/// returns the best move of the deepest search that finished
+fn best_move(position: &Position, level: Level) -> Option<SearchMove> {
+    let mut search = Search {
+        deadline: Instant::now() + level.movetime,
+        aborted: false,
+    };
+    let mut moves = legal_moves(position);
+    let mut best = moves.first().copied();
+    for depth in 1..=level.depth {
+        let mut alpha = -INFINITY;
+        let mut best_at_depth = None;
+        for &mov in &moves {
+            let score = -search.negamax(&make_move(position, mov), depth - 1, 1, -INFINITY, -alpha);
+            if search.aborted {
+                return best;
+            }
+            if score > alpha {
+                alpha = score;
+                best_at_depth = Some(mov);
+            }
+        }
+        best = best_at_depth.or(best);
+        // Search the best move first next time around
+        if let Some(mov) = best {
+            moves.retain(|&m| m != mov);
+            moves.insert(0, mov);
+        }
+        if alpha >= MATE - 1000 {
+            break;
+        }
+    }
+    best
+}
+
+struct Search {
+    deadline: Instant,
+    aborted: bool,
+}
+
+impl Search {
+    fn negamax(
+        &mut self,
+        position: &Position,
+        depth: u32,
+        ply: i32,
+        mut alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        if Instant::now() >= self.deadline {
+            self.aborted = true;
+        }
+        if self.aborted {
+            return 0;
+        }
+        if depth == 0 {
+            return self.quiesce(position, alpha, beta);
+        }
+
+        let moves = legal_moves(position);
+        if moves.is_empty() {
+            return match rules::in_check(&position.board, position.turn) {
+                // Prefer the quickest mate
+                true => -MATE + ply,
+                false => 0,
+            };
+        }
+        for mov in moves {
+            let score = -self.negamax(&make_move(position, mov), depth - 1, ply + 1, -beta, -alpha);
+            if score >= beta {
+                return beta;
+            }
+            alpha = alpha.max(score);
+        }
+        alpha
+    }
+
+    /// Follows captures until the position is quiet, so the evaluation does not stop
+    /// in the middle of an exchange
+    fn quiesce(&mut self, position: &Position, mut alpha: i32, beta: i32) -> i32 {
+        let stand_pat = evaluate(position);
+        if stand_pat >= beta {
+            return beta;
+        }
+        alpha = alpha.max(stand_pat);
+
+        let captures = legal_moves(position)
+            .into_iter()
+            .filter(|&(_, _, to)| is_capture(&position.board, to));
+        for mov in captures {
+            if self.aborted {
+                break;
+            }
+            let score = -self.quiesce(&make_move(position, mov), -beta, -alpha);
+            if score >= beta {
+                return beta;
+            }
+            alpha = alpha.max(score);
+        }
+        alpha
+    }
+}
+
+fn is_capture(board: &Board, to: Move) -> bool {
+    board.pieces[to.1 as usize][to.0 as usize].piece_type != PieceType::EMPTY
+}
+
+fn piece_value(piece_type: PieceType) -> i32 {
+    match piece_type {
+        PieceType::PAWN => 100,
+        PieceType::KNIGHT => 320,
+        PieceType::BISHOP => 330,
+        PieceType::ROOK => 500,
+        PieceType::QUEEN => 900,
+        PieceType::KING | PieceType::EMPTY => 0,
+    }
+}
+
+/// Legal moves of the side to move, captures of the most valuable pieces first
+fn legal_moves(position: &Position) -> Vec<SearchMove> {
+    let board = &position.board;
+    let mut moves = Vec::new();
+    for y in 0..8 {
+        for x in 0..8 {
+            let piece = board.pieces[y as usize][x as usize];
+            if piece.piece_type == PieceType::EMPTY || piece.color != position.turn {
+                continue;
+            }
+            for to in get_legal_moves(*board, x, y, position.turn) {
+                let castling = piece.piece_type == PieceType::KING && (to.0 - x).abs() == 2;
+                if castling
+                    && !position
+                        .castling
+                        .allows(piece.color, if to.0 > x { 7 } else { 0 })
+                {
+                    continue;
+                }
+                moves.push((x, y, to));
+            }
+            for to in rules::en_passant_captures(board, position.en_passant, x, y) {
+                if !moves.contains(&(x, y, to)) {
+                    moves.push((x, y, to));
+                }
+            }
+        }
+    }
+    moves.sort_by_key(|&(_, _, to)| {
+        -piece_value(board.pieces[to.1 as usize][to.0 as usize].piece_type)
+    });
+    moves
+}
+
+/// Plays a move on a copy of the position, including the rook of a castling move,
+/// the pawn taken en passant and promotion to a queen
+fn make_move(position: &Position, (from_x, from_y, to): SearchMove) -> Position {
+    let mut next = *position;
+    let board = &mut next.board;
+    let piece = board.pieces[from_y as usize][from_x as usize];
+
+    if piece.piece_type == PieceType::PAWN && Some(to) == position.en_passant {
+        rules::clear(board, to.0, from_y);
+    }
+    if piece.piece_type == PieceType::KING && (to.0 - from_x).abs() == 2 {
+        let (rook_from, rook_to) = if to.0 > from_x { (7, 5) } else { (0, 3) };
+        board.pieces[from_y as usize][rook_to] = board.pieces[from_y as usize][rook_from];
+        rules::clear(board, rook_from as i32, from_y);
+    }
+    board.pieces[to.1 as usize][to.0 as usize] = piece;
+    rules::clear(board, from_x, from_y);
+    if crate::is_promotion(piece.piece_type, to) {
+        board.pieces[to.1 as usize][to.0 as usize].piece_type = PieceType::QUEEN;
+    }
+
+    next.castling.clear_square(from_x as usize, from_y as usize);
+    next.castling.clear_square(to.0 as usize, to.1 as usize);
+    next.en_passant = match piece.piece_type == PieceType::PAWN && (to.1 - from_y).abs() == 2 {
+        true => Some(Move(to.0, (to.1 + from_y) / 2)),
+        false => None,
+    };
+    next.turn = rules::opponent(position.turn);
+    next
+}
+
+/// Material and piece placement, from the point of view of the side to move
+fn evaluate(position: &Position) -> i32 {
+    let mut score = 0;
+    for (y, row) in position.board.pieces.iter().enumerate() {
+        for (x, piece) in row.iter().enumerate() {
+            let table = match piece.piece_type {
+                PieceType::PAWN => &PAWN_TABLE,
+                PieceType::KNIGHT => &KNIGHT_TABLE,
+                PieceType::BISHOP => &BISHOP_TABLE,
+                PieceType::ROOK => &ROOK_TABLE,
+                PieceType::QUEEN => &QUEEN_TABLE,
+                PieceType::KING => &KING_TABLE,
+                PieceType::EMPTY => continue,
+            };
+            // Black reads the tables upside down
+            let row = match piece.color {
+                Color::WHITE => y,
+                _ => 7 - y,
+            };
+            let value = piece_value(piece.piece_type) + table[row][x];
+            score += match piece.color == position.turn {
+                true => value,
+                false => -value,
+            };
+        }
+    }
+    score
+}
@@ -0,0 +1,45 @@
+use crate::PINK_COLOR;
+use ggez::glam::vec2;
+use ggez::graphics::{self, Canvas, Rect, TextLayout};
+use ggez::{Context, GameResult};
+
+/// Clickable text button drawn in screen coordinates
+pub struct Button {
+    pub rect: Rect,
+    mesh: graphics::Mesh,
+    label: graphics::Text,
+}
+
+impl Button {
+    pub fn new(ctx: &mut Context, rect: Rect, label: &str) -> GameResult<Button> {
+        let mb = &mut graphics::MeshBuilder::new();
+        mb.rectangle(
+            graphics::DrawMode::fill(),
+            Rect::new(0.0, 0.0, rect.w, rect.h),
+            graphics::Color::from_rgb(60, 60, 60),
+        )?;
+        mb.rectangle(
+            graphics::DrawMode::stroke(3.0),
+            Rect::new(0.0, 0.0, rect.w, rect.h),
+            PINK_COLOR,
+        )?;
+
+        Ok(Button {
+            rect,
+            mesh: graphics::Mesh::from_data(ctx, mb.build()),
+            label: graphics::Text::new(label)
+                .set_layout(TextLayout::center())
+                .set_scale(20.0)
+                .clone(),
+        })
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas) {
+        canvas.draw(&self.mesh, self.rect.point());
+        canvas.draw(&self.label, self.rect.center());
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.rect.contains(vec2(x, y))
+    }
+}
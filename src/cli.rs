@@ -0,0 +1,188 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::env;
+use std::path::PathBuf;
+
+/// Chess over the network, against the computer, an engine or someone at the same screen
+#[derive(Parser)]
+#[command(name = "chess", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Window size in pixels, the board is scaled to fit
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_window_size, global = true)]
+    pub window_size: Option<(f32, f32)>,
+
+    /// Directory games are saved to as PGN when they end or when S is pressed
+    #[arg(long, value_name = "DIR", default_value = "games", global = true)]
+    pub pgn_dir: PathBuf,
+
+    /// File the position is appended to as FEN when F is pressed, printed without it
+    #[arg(long, value_name = "FILE", global = true)]
+    pub fen_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Host a game and wait for an opponent to join
+    Host {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: String,
+
+        #[command(flatten)]
+        game: GameOptions,
+    },
+    /// Join a game hosted by someone else
+    Join {
+        /// Address of the host
+        address: String,
+
+        /// Port the host is listening on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        #[command(flatten)]
+        game: GameOptions,
+    },
+    /// Play both colors from this window, without a network connection
+    Local {
+        #[command(flatten)]
+        setup: SetupOptions,
+
+        /// Let a script play the second color: moves like e7e5 or e7e8q separated by
+        /// whitespace, the opponent resigns when it runs out of them
+        #[arg(long, value_name = "FILE")]
+        script: Option<PathBuf>,
+    },
+    /// Play against the built-in computer opponent
+    Ai {
+        /// Strength from 1 to 5, higher levels search deeper and think longer
+        #[arg(short, long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=5))]
+        level: u8,
+
+        /// Color you would like to play
+        #[arg(short, long, value_enum, default_value_t = ColorChoice::Random)]
+        color: ColorChoice,
+
+        #[command(flatten)]
+        setup: SetupOptions,
+    },
+    /// Play against a UCI engine such as Stockfish
+    Engine {
+        /// Path to the engine executable
+        path: PathBuf,
+
+        /// Value for the engine's Skill Level option
+        #[arg(long)]
+        skill_level: Option<u32>,
+
+        /// Value for the engine's Threads option
+        #[arg(long)]
+        threads: Option<u32>,
+
+        /// Milliseconds the engine thinks about each move
+        #[arg(long, default_value_t = 1000)]
+        movetime: u64,
+
+        /// Color you would like to play
+        #[arg(short, long, value_enum, default_value_t = ColorChoice::Random)]
+        color: ColorChoice,
+
+        #[command(flatten)]
+        setup: SetupOptions,
+    },
+    /// Resume a networked game that was interrupted, both players resume their own save
+    Resume {
+        /// Save file written during the game, next to its PGN export
+        file: PathBuf,
+
+        /// Address of the host to rejoin, the game is hosted again without it
+        #[arg(long, value_name = "ADDRESS")]
+        join: Option<String>,
+
+        /// Port to listen on, or the host is listening on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        /// Address to listen on when hosting
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: String,
+
+        /// Seconds to wait for the opponent to acknowledge a move
+        #[arg(long, default_value_t = 10.0)]
+        ack_timeout: f32,
+    },
+    /// Step through a game saved as PGN with the arrow keys
+    Replay {
+        /// PGN file to show, only its first game is read
+        file: PathBuf,
+    },
+}
+
+/// Options negotiated with the opponent in the Start handshake
+#[derive(Args)]
+pub struct GameOptions {
+    /// Name shown to the opponent, defaults to your user name
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Color you would like to play, the game is not started if both players ask for the same one
+    #[arg(short, long, value_enum, default_value_t = ColorChoice::Random)]
+    pub color: ColorChoice,
+
+    #[command(flatten)]
+    pub setup: SetupOptions,
+
+    /// Seconds to wait for the opponent to acknowledge a move
+    #[arg(long, default_value_t = 10.0)]
+    pub ack_timeout: f32,
+}
+
+/// Time control and starting position, shared by networked and local games
+#[derive(Args)]
+pub struct SetupOptions {
+    /// Thinking time per player in seconds, the game is untimed without it
+    #[arg(long)]
+    pub time: Option<u64>,
+
+    /// Seconds added to a player's clock after each of their moves
+    #[arg(long, requires = "time")]
+    pub inc: Option<u64>,
+
+    /// Position to start from instead of the standard starting position
+    #[arg(long)]
+    pub fen: Option<String>,
+}
+
+impl GameOptions {
+    pub fn player_name(&self) -> String {
+        self.name
+            .clone()
+            .or_else(|| env::var("USER").ok())
+            .or_else(|| env::var("USERNAME").ok())
+            .unwrap_or_else(|| "Anonymous".to_string())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum ColorChoice {
+    White,
+    Black,
+    Random,
+}
+
+fn parse_window_size(size: &str) -> Result<(f32, f32), String> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or("expected WIDTHxHEIGHT, e.g. 800x800")?;
+    let parse = |value: &str| match value.parse::<u32>() {
+        Ok(value) if value >= 100 => Ok(value as f32),
+        _ => Err(format!("invalid window dimension '{}'", value)),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
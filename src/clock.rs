@@ -0,0 +1,116 @@
+use chess_lib::board::pieces::Color;
+use std::time::{Duration, Instant};
+
+/// Chess clock counting down the thinking time of both players
+pub struct Clock {
+    white: Duration,
+    black: Duration,
+    // Thinking time both players started with
+    initial: Duration,
+    increment: Duration,
+    // Color whose time is running and since when
+    running: Option<(Color, Instant)>,
+}
+
+impl Clock {
+    pub fn new(time: Duration, increment: Duration) -> Clock {
+        Clock {
+            white: time,
+            black: time,
+            initial: time,
+            increment,
+            running: None,
+        }
+    }
+
+    /// Clock for a time control given in seconds, None for untimed games
+    pub fn from_time_control(time: Option<u64>, inc: Option<u64>) -> Option<Clock> {
+        time.map(|time| {
+            Clock::new(
+                Duration::from_secs(time),
+                Duration::from_secs(inc.unwrap_or(0)),
+            )
+        })
+    }
+
+    /// Starting time and increment per move
+    pub fn time_control(&self) -> (Duration, Duration) {
+        (self.initial, self.increment)
+    }
+
+    /// Sets the time left for `color`, e.g. when resuming a game
+    pub fn set_remaining(&mut self, color: Color, remaining: Duration) {
+        match color {
+            Color::WHITE => self.white = remaining,
+            _ => self.black = remaining,
+        }
+    }
+
+    /// Time left for `color`, including the time spent on the current move
+    pub fn remaining(&self, color: Color) -> Duration {
+        let stored = match color {
+            Color::WHITE => self.white,
+            _ => self.black,
+        };
+        match self.running {
+            Some((running, since)) if running == color => stored.saturating_sub(since.elapsed()),
+            _ => stored,
+        }
+    }
+
+    /// Time left for white and for black, to be put back later with `restore`
+    pub fn times(&self) -> (Duration, Duration) {
+        (self.remaining(Color::WHITE), self.remaining(Color::BLACK))
+    }
+
+    /// Puts back the times from `times` and starts the clock of `color`
+    pub fn restore(&mut self, (white, black): (Duration, Duration), color: Color) {
+        self.running = None;
+        self.white = white;
+        self.black = black;
+        self.start(color);
+    }
+
+    pub fn running(&self) -> Option<Color> {
+        self.running.map(|(color, _)| color)
+    }
+
+    pub fn start(&mut self, color: Color) {
+        self.stop();
+        self.running = Some((color, Instant::now()));
+    }
+
+    /// Stops the running clock, keeping the time it used
+    pub fn stop(&mut self) {
+        if let Some((color, _)) = self.running {
+            let remaining = self.remaining(color);
+            self.set_remaining(color, remaining);
+            self.running = None;
+        }
+    }
+
+    /// Ends the move of the running side: stops its clock, adds the increment and
+    /// starts the clock of `next`
+    pub fn complete_move(&mut self, next: Color) {
+        if let Some((color, _)) = self.running {
+            self.stop();
+            match color {
+                Color::WHITE => self.white += self.increment,
+                _ => self.black += self.increment,
+            }
+        }
+        self.start(next);
+    }
+
+    /// The color that has run out of time, if any
+    pub fn flagged(&self) -> Option<Color> {
+        self.running()
+            .filter(|&color| self.remaining(color) == Duration::ZERO)
+    }
+}
+
+/// Formats a duration as m:ss
+pub fn format_time(time: Duration) -> String {
+    let secs = time.as_secs_f32().ceil() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
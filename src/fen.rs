@@ -0,0 +1,364 @@
+use chess_lib::board::pieces::{Color, Move, PieceType};
+use chess_lib::board::Board;
+use chess_lib::game::Game;
+use ggez::GameError;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::rules::CastlingRights;
+
+pub const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// A parsed Forsyth-Edwards Notation position
+#[derive(Clone, Debug)]
+pub struct Fen {
+    pub placement: String,
+    pub turn: Color,
+    pub castling: CastlingRights,
+    pub en_passant: Option<Move>,
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+#[derive(Debug)]
+pub enum FenError {
+    Placement,
+    SideToMove,
+    Castling,
+    EnPassant,
+    Counter,
+}
+
+impl fmt::Display for FenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = match self {
+            FenError::Placement => "piece placement",
+            FenError::SideToMove => "side to move",
+            FenError::Castling => "castling rights",
+            FenError::EnPassant => "en passant square",
+            FenError::Counter => "move counters",
+        };
+        write!(f, "Invalid FEN: bad {}", field)
+    }
+}
+
+impl From<FenError> for GameError {
+    fn from(err: FenError) -> GameError {
+        GameError::CustomError(err.to_string())
+    }
+}
+
+impl Fen {
+    /// Parses and validates a FEN string. The move counters may be left out.
+    pub fn parse(fen: &str) -> Result<Fen, FenError> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() < 4 || fields.len() > 6 {
+            return Err(FenError::Placement);
+        }
+
+        let placement = fields[0];
+        if !valid_placement(placement) {
+            return Err(FenError::Placement);
+        }
+
+        let turn = match fields[1] {
+            "w" => Color::WHITE,
+            "b" => Color::BLACK,
+            _ => return Err(FenError::SideToMove),
+        };
+
+        let castling = CastlingRights::parse(fields[2]).ok_or(FenError::Castling)?;
+
+        let en_passant = match fields[3] {
+            "-" => None,
+            square => {
+                let square = parse_square(square).ok_or(FenError::EnPassant)?;
+                // Rank 6 after a black double push, rank 3 after a white one
+                let expected_y = if turn == Color::WHITE { 2 } else { 5 };
+                if square.1 != expected_y || !double_push(placement, turn, square) {
+                    return Err(FenError::EnPassant);
+                }
+                Some(square)
+            }
+        };
+
+        let counter = |i: usize, default: u32| match fields.get(i) {
+            Some(field) => field.parse::<u32>().map_err(|_| FenError::Counter),
+            None => Ok(default),
+        };
+
+        Ok(Fen {
+            placement: placement.to_string(),
+            turn,
+            castling,
+            en_passant,
+            halfmove_clock: counter(4, 0)?,
+            fullmove_number: counter(5, 1)?.max(1),
+        })
+    }
+
+    /// Creates a chess_lib game from the position. chess_lib only takes the piece
+    /// placement, the side to move is set afterwards.
+    pub fn to_game(&self) -> Game {
+        let mut game = Game::new(Some(self.placement.clone()));
+        game.turn = self.turn;
+        game
+    }
+}
+
+impl fmt::Display for Fen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let turn = match self.turn {
+            Color::WHITE => "w",
+            _ => "b",
+        };
+        let en_passant = self.en_passant.map_or("-".to_string(), square_name);
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            self.placement,
+            turn,
+            self.castling,
+            en_passant,
+            self.halfmove_clock,
+            self.fullmove_number
+        )
+    }
+}
+
+/// Appends `fen` as a line of its own to `file`, or prints it without one
+pub fn export(fen: &Fen, file: Option<&Path>) -> io::Result<()> {
+    match file {
+        Some(file) => {
+            let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+            writeln!(file, "{}", fen)
+        }
+        None => {
+            println!("{}", fen);
+            Ok(())
+        }
+    }
+}
+
+/// Board coordinates of an algebraic square such as "e3"
+pub fn parse_square(square: &str) -> Option<Move> {
+    let mut chars = square.chars();
+    let file = chars.next()?;
+    let rank = chars.next()?;
+    if chars.next().is_some() || !('a'..='h').contains(&file) || !('1'..='8').contains(&rank) {
+        return None;
+    }
+    Some(Move(file as i32 - 'a' as i32, '8' as i32 - rank as i32))
+}
+
+/// Algebraic name of the square at board coordinates, e.g. "e3"
+pub fn square_name(square: Move) -> String {
+    format!(
+        "{}{}",
+        (b'a' + square.0 as u8) as char,
+        (b'8' - square.1 as u8) as char
+    )
+}
+
+/// Checks for eight ranks of eight squares, known piece letters and one king per side
+fn valid_placement(placement: &str) -> bool {
+    let ranks: Vec<&str> = placement.split('/').collect();
+    if ranks.len() != 8 {
+        return false;
+    }
+    for rank in &ranks {
+        let mut squares = 0;
+        for c in rank.chars() {
+            match c {
+                '1'..='8' => squares += c as u32 - '0' as u32,
+                'p' | 'r' | 'n' | 'b' | 'q' | 'k' | 'P' | 'R' | 'N' | 'B' | 'Q' | 'K' => {
+                    squares += 1
+                }
+                _ => return false,
+            }
+        }
+        if squares != 8 {
+            return false;
+        }
+    }
+    // Pawns can never stand on the first or last rank
+    if ranks[0].contains(['p', 'P']) || ranks[7].contains(['p', 'P']) {
+        return false;
+    }
+    placement.matches('K').count() == 1 && placement.matches('k').count() == 1
+}
+
+/// Piece letter on the square at board coordinates, None if it is empty
+fn placement_char(placement: &str, square: Move) -> Option<char> {
+    let rank = placement.split('/').nth(square.1 as usize)?;
+    let mut x = 0;
+    for c in rank.chars() {
+        match c.to_digit(10) {
+            Some(empty) => x += empty as i32,
+            None => x += 1,
+        }
+        if x > square.0 {
+            return c.is_ascii_alphabetic().then_some(c);
+        }
+    }
+    None
+}
+
+/// Whether the placement fits a pawn of the side not to move having just skipped
+/// over `square`: the pawn stands right past it, and both the square and the one the
+/// pawn started from are empty
+fn double_push(placement: &str, turn: Color, square: Move) -> bool {
+    let (pawn, dy) = match turn {
+        Color::WHITE => ('p', 1),
+        _ => ('P', -1),
+    };
+    placement_char(placement, Move(square.0, square.1 + dy)) == Some(pawn)
+        && placement_char(placement, square).is_none()
+        && placement_char(placement, Move(square.0, square.1 - dy)).is_none()
+}
+
+pub fn piece_char(piece_type: PieceType, color: Color) -> char {
+    let c = match piece_type {
+        PieceType::PAWN => 'p',
+        PieceType::ROOK => 'r',
+        PieceType::BISHOP => 'b',
+        PieceType::KNIGHT => 'n',
+        PieceType::QUEEN => 'q',
+        PieceType::KING => 'k',
+        PieceType::EMPTY => '1',
+    };
+    match color {
+        Color::WHITE => c.to_ascii_uppercase(),
+        _ => c,
+    }
+}
+
+/// Piece placement field of the FEN for `board`, rank 8 first
+pub fn placement(board: &Board) -> String {
+    let mut fen = String::new();
+    for (y, row) in board.pieces.iter().enumerate() {
+        let mut empty = 0;
+        for piece in row {
+            if piece.piece_type == PieceType::EMPTY {
+                empty += 1;
+                continue;
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+                empty = 0;
+            }
+            fen.push(piece_char(piece.piece_type, piece.color));
+        }
+        if empty > 0 {
+            fen.push_str(&empty.to_string());
+        }
+        if y < 7 {
+            fen.push('/');
+        }
+    }
+    fen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_starting_position() {
+        let fen = Fen::parse(STARTING_FEN).unwrap();
+        assert_eq!(fen.placement, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR");
+        assert_eq!(fen.turn, Color::WHITE);
+        assert_eq!(fen.castling.to_string(), "KQkq");
+        assert_eq!(fen.en_passant, None);
+        assert_eq!((fen.halfmove_clock, fen.fullmove_number), (0, 1));
+    }
+
+    #[test]
+    fn round_trips() {
+        for text in [
+            STARTING_FEN,
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2",
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+            "r3k2r/8/8/8/8/8/8/R3K2R b Kq - 17 42",
+            "8/8/4k3/8/8/4K3/8/8 w - - 0 80",
+        ] {
+            assert_eq!(Fen::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn counters_are_optional() {
+        let fen = Fen::parse("8/8/4k3/8/8/4K3/8/8 b - -").unwrap();
+        assert_eq!(fen.to_string(), "8/8/4k3/8/8/4K3/8/8 b - - 0 1");
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        let bad = [
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP w KQkq - 0 1",
+                "piece placement",
+            ),
+            (
+                "rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "piece placement",
+            ),
+            (
+                "rnbqqbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "piece placement",
+            ),
+            (
+                "Pnbqkbnr/pppppppp/8/8/8/8/1PPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "piece placement",
+            ),
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1",
+                "side to move",
+            ),
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KX - 0 1",
+                "castling rights",
+            ),
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e9 0 1",
+                "en passant square",
+            ),
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - x 1",
+                "move counters",
+            ),
+        ];
+        for (text, field) in bad {
+            let err = Fen::parse(text).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid FEN: bad {}", field),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn en_passant_square_needs_a_double_push() {
+        // Wrong rank for the side to move
+        assert!(Fen::parse("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e3 0 1").is_err());
+        // No pawn past the square
+        assert!(Fen::parse("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq e3 0 1").is_err());
+        // The pawn could not have come from its starting square
+        assert!(Fen::parse("rnbqkbnr/pppppppp/8/8/4P3/8/PPPPNPPP/RNBQKB1R b KQkq e3 0 1").is_err());
+    }
+
+    #[test]
+    fn squares() {
+        assert_eq!(parse_square("a8"), Some(Move(0, 0)));
+        assert_eq!(parse_square("e3"), Some(Move(4, 5)));
+        assert_eq!(parse_square("h1"), Some(Move(7, 7)));
+        assert_eq!(parse_square("i1"), None);
+        assert_eq!(parse_square("e"), None);
+        assert_eq!(parse_square("e33"), None);
+        assert_eq!(square_name(Move(4, 5)), "e3");
+    }
+}
@@ -57,17 +57,21 @@ impl Grid {
         })
     }
 
-    pub fn screen2grid(&self, x: f32, y: f32) -> Option<GridPosition> {
-        if x - GRID_X < 0.0 || x - GRID_X > TILE_SIZE * 8.0 {
+    /// Board square under screen position (x, y), with rank 1 at the top if `flipped`
+    pub fn screen2grid(&self, x: f32, y: f32, flipped: bool) -> Option<GridPosition> {
+        if x - GRID_X < 0.0 || x - GRID_X >= TILE_SIZE * 8.0 {
             return None;
         }
-        if y - GRID_Y < 0.0 || y - GRID_Y > TILE_SIZE * 8.0 {
+        if y - GRID_Y < 0.0 || y - GRID_Y >= TILE_SIZE * 8.0 {
             return None;
         }
 
         let grid_x = ((x - GRID_X) / (TILE_SIZE)) as usize;
         let grid_y = ((y - GRID_Y) / (TILE_SIZE)) as usize;
 
-        Some((grid_x, grid_y).into())
+        match flipped {
+            true => Some((7 - grid_x, 7 - grid_y).into()),
+            false => Some((grid_x, grid_y).into()),
+        }
     }
 }
@@ -1,18 +1,46 @@
-use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use chess_lib::board::pieces::{get_legal_moves, move_piece, Color, Move, PieceType};
+use ai::{AiOpponent, Level};
+use button::Button;
+use chess_lib::board::pieces::{Color, Move, PieceType};
 use chess_lib::game::Game;
-use chess_networking::Start;
+use chess_networking::{GameState, PromotionPiece, Start};
+use clap::Parser;
+use cli::{Cli, ColorChoice, Command, GameOptions, SetupOptions};
+use clock::Clock;
+use fen::Fen;
 use ggez::conf::{WindowMode, WindowSetup};
-use ggez::event::{self, EventHandler, MouseButton};
+use ggez::event::{self, EventHandler, EventLoop, MouseButton};
 use ggez::glam::{vec2, Vec2};
 use ggez::graphics::{self, Canvas, DrawParam, Rect, TextLayout};
-use ggez::{Context, ContextBuilder, GameResult};
+use ggez::input::keyboard::{KeyCode, KeyInput};
+use ggez::{Context, ContextBuilder, GameError, GameResult};
 use grid::{Grid, GridPosition};
-use networking::{Connection, MultiplayerStatus};
+use networking::{Connection, Message, MultiplayerStatus, ReadError, ResumeMoves, Takeback};
+use opponent::{LocalHuman, Opponent, ScriptedOpponent};
+use pgn::Pgn;
+use replay::Replay;
+use rules::{Ending, PlayedMove, PositionHistory};
+use save::SavedGame;
+use serde::{Deserialize, Serialize};
+use uci::{EngineSettings, UciEngine};
 
+pub mod ai;
+pub mod button;
+pub mod cli;
+pub mod clock;
+pub mod fen;
 pub mod grid;
 pub mod networking;
+pub mod opponent;
+pub mod pgn;
+pub mod replay;
+pub mod rules;
+pub mod san;
+pub mod save;
+pub mod uci;
 
 const TILE_SIZE: f32 = 100.0;
 const BLACK_COLOR: graphics::Color = graphics::Color::BLACK;
@@ -28,104 +56,463 @@ const PINK_COLOR: graphics::Color = graphics::Color {
 const GRID_X: f32 = 100.0;
 const GRID_Y: f32 = 100.0;
 
-fn main() -> GameResult {
-    let status = match env::args()
-        .nth(1)
-        .expect("Usage: chess <client|server>")
-        .as_str()
-    {
-        "client" => MultiplayerStatus::Client,
-        "server" => MultiplayerStatus::Server,
-        _ => panic!("Usage: chess <client|server>"),
-    };
+// Move list to the right of the board and the clocks
+const PANEL_X: f32 = TILE_SIZE * 8.0 + GRID_X * 2.0;
+const PANEL_WIDTH: f32 = 250.0;
+const MOVE_LIST_ROW_HEIGHT: f32 = 24.0;
+const MOVE_LIST_ROWS: usize = 32;
 
-    let mut connection = match status {
-        MultiplayerStatus::Server => {
-            let port = env::args()
-                .nth(2)
-                .expect("Please supply port")
-                .parse::<u16>()
-                .expect("Invalid port number!");
+// Size of the drawing area, scaled to whatever size the window has
+const WINDOW_WIDTH: f32 = PANEL_X + PANEL_WIDTH;
+const WINDOW_HEIGHT: f32 = TILE_SIZE * 8.0 + GRID_Y * 2.0;
+
+// How often a game in progress is saved for resuming
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Pieces offered in the promotion chooser, from the promotion square inwards
+const PROMOTION_CHOICES: [PromotionPiece; 4] = [
+    PromotionPiece::Queen,
+    PromotionPiece::Rook,
+    PromotionPiece::Bishop,
+    PromotionPiece::Knight,
+];
 
-            println!("Waiting for client to connect...");
-            Connection::server(port)
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> GameResult {
+    let setup = match &cli.command {
+        Command::Host { game, .. } | Command::Join { game, .. } => Some(&game.setup),
+        Command::Local { setup, .. }
+        | Command::Ai { setup, .. }
+        | Command::Engine { setup, .. } => Some(setup),
+        Command::Resume { .. } => None,
+        Command::Replay { file } => return run_replay(file, &cli),
+    };
+    if let Some(fen) = setup.and_then(|setup| setup.fen.as_ref()) {
+        Fen::parse(fen)?;
+    }
+
+    let setup = match cli.command {
+        Command::Host { port, bind, game } => {
+            start_networked(listen(&bind, port)?, &game, &cli.pgn_dir)?
         }
-        MultiplayerStatus::Client => {
-            let addr = env::args().nth(2).expect("Please supply address and port");
-            let port = env::args()
-                .nth(3)
-                .expect("Please supply port")
-                .parse::<u16>()
-                .expect("Invalid port number!");
+        Command::Join {
+            address,
+            port,
+            game,
+        } => start_networked(connect(&address, port)?, &game, &cli.pgn_dir)?,
+        Command::Resume {
+            file,
+            join,
+            port,
+            bind,
+            ack_timeout,
+        } => {
+            // Check the save before making the opponent wait on us
+            let saved = save::load(&file)?;
+            let connection = match join {
+                Some(address) => connect(&address, port)?,
+                None => listen(&bind, port)?,
+            };
+            resume_networked(connection, saved, file, ack_timeout)?
+        }
+        Command::Local { setup, script } => {
+            let (clock, start) = local_setup(&setup)?;
+            let opponent: Box<dyn Opponent> = match script {
+                // The script answers the player who moves first
+                Some(path) => Box::new(ScriptedOpponent::from_file(
+                    &path,
+                    rules::opponent(start.turn),
+                    false,
+                )?),
+                None => Box::<LocalHuman>::default(),
+            };
+            GameSetup::local(opponent, clock, start)
+        }
+        Command::Ai {
+            level,
+            color,
+            setup,
+        } => {
+            let (clock, start) = local_setup(&setup)?;
+            let color = rules::opponent(choose_color(color));
+            let opponent = Box::new(AiOpponent::new(color, Level::new(level), &start));
+            GameSetup::local(opponent, clock, start)
+        }
+        Command::Engine {
+            path,
+            skill_level,
+            threads,
+            movetime,
+            color,
+            setup,
+        } => {
+            let (clock, start) = local_setup(&setup)?;
+            let settings = EngineSettings {
+                skill_level,
+                threads,
+                movetime: Duration::from_millis(movetime),
+            };
+            let color = rules::opponent(choose_color(color));
+            let engine = UciEngine::launch(&path, &settings, color, &start)?;
+            GameSetup::local(Box::new(engine), clock, start)
+        }
+        Command::Replay { .. } => unreachable!("replays do not set up a game"),
+    };
+
+    let (mut ctx, event_loop) = build_context(cli.window_size)?;
+
+    // Create an instance of your event handler.
+    // Usually, you should provide it with the Context object to
+    // use when setting your game up.
+    let my_game = Chess::new(&mut ctx, setup, cli.pgn_dir, cli.fen_file)?;
+
+    // Run!
+    event::run(ctx, event_loop, my_game);
+}
 
-            println!("Waiting for server to respond...");
-            Connection::client(&addr, port)
+/// Opens the window, `window_size` defaulting to the size everything is laid out for
+fn build_context(window_size: Option<(f32, f32)>) -> GameResult<(Context, EventLoop<()>)> {
+    let (width, height) = window_size.unwrap_or((WINDOW_WIDTH, WINDOW_HEIGHT));
+    // Make a Context.
+    ContextBuilder::new("Chess", "Cool Game Author")
+        .window_setup(
+            WindowSetup::default()
+                .title("Cool chess game")
+                .samples(ggez::conf::NumSamples::Four),
+        )
+        .window_mode(WindowMode::default().dimensions(width, height))
+        .add_resource_path("./resources")
+        .build()
+}
+
+/// Shows the game in a PGN file, checking all of its moves before the window opens
+fn run_replay(path: &Path, cli: &Cli) -> GameResult {
+    let (pgn, _, history) = replay::load(path)?;
+    let (mut ctx, event_loop) = build_context(cli.window_size)?;
+    let replay = Replay::new(&mut ctx, pgn, history.moves, cli.fen_file.clone())?;
+    event::run(ctx, event_loop, replay);
+}
+
+/// Clock and starting position of a game without a network peer
+fn local_setup(setup: &SetupOptions) -> GameResult<(Option<Clock>, Fen)> {
+    let start = Fen::parse(setup.fen.as_deref().unwrap_or(fen::STARTING_FEN))?;
+    Ok((Clock::from_time_control(setup.time, setup.inc), start))
+}
+
+/// Everything about a game that is settled before the window opens
+pub struct GameSetup {
+    opponent: Box<dyn Opponent>,
+    ack_timeout: Duration,
+    clock: Option<Clock>,
+    start: Fen,
+    /// Moves already played from `start`, when resuming a saved game
+    moves: Vec<PlayedMove>,
+    /// Where the game is saved so it can be resumed, None for games that cannot be
+    save_file: Option<PathBuf>,
+    /// When the game started, which also names its PGN file
+    started: SystemTime,
+}
+
+impl GameSetup {
+    /// A new game against an opponent in this process
+    fn local(opponent: Box<dyn Opponent>, clock: Option<Clock>, start: Fen) -> GameSetup {
+        GameSetup {
+            opponent,
+            // Local opponents answer within the same update
+            ack_timeout: Duration::ZERO,
+            clock,
+            start,
+            moves: Vec::new(),
+            save_file: None,
+            started: SystemTime::now(),
         }
-    }?;
+    }
+}
 
+fn listen(bind: &str, port: u16) -> GameResult<Connection> {
+    println!("Waiting for client to connect on {}:{}...", bind, port);
+    Connection::server(bind, port).map_err(|e| {
+        GameError::CustomError(format!("Could not listen on {}:{}: {}", bind, port, e))
+    })
+}
+
+fn connect(address: &str, port: u16) -> GameResult<Connection> {
+    println!("Waiting for server to respond...");
+    Connection::client(address, port).map_err(|e| {
+        GameError::CustomError(format!("Could not connect to {}:{}: {}", address, port, e))
+    })
+}
+
+/// Runs the handshake on a fresh connection, giving what Chess::new needs. The game
+/// is saved next to its PGN export.
+fn start_networked(
+    mut connection: Connection,
+    options: &GameOptions,
+    pgn_dir: &Path,
+) -> GameResult<GameSetup> {
+    println!("Connected!");
+
+    let (clock, start) = handshake(&mut connection, options)?;
+    println!(
+        "I am {:?} and {:?}",
+        connection.multiplayer_status, connection.local_color
+    );
+    let local_name = connection.local_name.as_str();
+    let opponent_name = connection.opponent_name.as_deref().unwrap_or("?");
+    let (white, black) = match connection.local_color {
+        Color::WHITE => (local_name, opponent_name),
+        _ => (opponent_name, local_name),
+    };
+    // The PGN file gets the same name, so the save is found next to it
+    let started = SystemTime::now();
+    let save_file = pgn_dir
+        .join(pgn::file_name(started, white, black))
+        .with_extension("save");
+    println!("Saving the game to {} for resuming", save_file.display());
+    Ok(GameSetup {
+        opponent: Box::new(connection),
+        ack_timeout: Duration::from_secs_f32(options.ack_timeout),
+        clock,
+        start,
+        moves: Vec::new(),
+        save_file: Some(save_file),
+        started,
+    })
+}
+
+/// Runs the resume handshake on a fresh connection and picks the saved game up where
+/// it was left, saving on to the same file
+fn resume_networked(
+    mut connection: Connection,
+    mut saved: SavedGame,
+    save_file: PathBuf,
+    ack_timeout: f32,
+) -> GameResult<GameSetup> {
     println!("Connected!");
 
-    if connection.multiplayer_status == MultiplayerStatus::Client {
+    let common = resume_handshake(&mut connection, &saved)?;
+    saved.moves.truncate(common);
+    println!(
+        "Resuming as {:?} after {} moves",
+        connection.local_color,
+        saved.moves.len()
+    );
+    Ok(GameSetup {
+        opponent: Box::new(connection),
+        ack_timeout: Duration::from_secs_f32(ack_timeout),
+        clock: saved.clock,
+        start: saved.start,
+        moves: saved.moves,
+        save_file: Some(save_file),
+        started: SystemTime::now(),
+    })
+}
+
+/// Exchanges Start packets to settle colors, time control and starting position.
+/// Where the two sides disagree the server's values take precedence.
+///
+/// Colors follow this rule: `is_white` in a Start packet always describes the
+/// sender's color. The client sends the color it asks for (a coin flip if it has no
+/// preference). The server keeps its own preference if it has one and otherwise takes
+/// the opposite of the client's request, then answers with the color it took. If both
+/// sides end up with the same color, both abort: the client when it asked for that
+/// color specifically, and the server whenever it kept its own preference, since the
+/// packet does not tell it whether the client's request was a coin flip.
+fn handshake(
+    connection: &mut Connection,
+    options: &GameOptions,
+) -> GameResult<(Option<Clock>, Fen)> {
+    connection.local_name = options.player_name();
+
+    // Time control in seconds
+    let (time, inc, fen) = if connection.multiplayer_status == MultiplayerStatus::Client {
         // Client
+        let wants_white = match options.color {
+            ColorChoice::White => true,
+            ColorChoice::Black => false,
+            ColorChoice::Random => coin_flip(),
+        };
         connection.write(Start {
-            is_white: true,
-            name: Some("Skibidi ohio".to_string()),
-            fen: None,
-            time: None,
-            inc: None,
+            is_white: wants_white,
+            name: Some(connection.local_name.clone()),
+            fen: options.setup.fen.clone(),
+            time: options.setup.time,
+            inc: options.setup.inc,
         })?;
         let packet: chess_networking::Start = connection.read_block()?;
-        //println!("{:?}", packet);
-        connection.local_color = match packet.is_white {
-            false => Color::WHITE,
-            true => Color::BLACK,
-        };
+        connection.opponent_name = packet.name.clone();
+        if options.color != ColorChoice::Random && packet.is_white == wants_white {
+            return Err(GameError::CustomError(format!(
+                "Color mismatch: both players want to play {}",
+                if wants_white { "white" } else { "black" }
+            )));
+        }
+        connection.local_color = color_from_white(!packet.is_white);
+        (packet.time, packet.inc, packet.fen)
     } else {
         // Server
         let packet: chess_networking::Start = connection.read_block()?;
-        //println!("{:?}", packet);
+        connection.opponent_name = packet.name.clone();
+        let (time, inc) = match options.setup.time {
+            Some(time) => (Some(time), options.setup.inc),
+            None => (packet.time, packet.inc),
+        };
+        let fen = options.setup.fen.clone().or(packet.fen);
+        let is_white = match options.color {
+            ColorChoice::White => true,
+            ColorChoice::Black => false,
+            ColorChoice::Random => !packet.is_white,
+        };
         connection.write(Start {
-            is_white: !packet.is_white,
-            name: Some("Fortnite roblox".to_string()),
-            fen: None,
-            time: None,
-            inc: None,
+            is_white,
+            name: Some(connection.local_name.clone()),
+            fen: fen.clone(),
+            time,
+            inc,
         })?;
-        connection.local_color = match packet.is_white {
-            false => Color::WHITE,
-            true => Color::BLACK,
-        };
+        // Answered first so the client can report the mismatch as well
+        if is_white == packet.is_white {
+            return Err(GameError::CustomError(format!(
+                "Color mismatch: both players want to play {}",
+                if is_white { "white" } else { "black" }
+            )));
+        }
+        connection.local_color = color_from_white(is_white);
+        (time, inc, fen)
+    };
+    // Peers running other clients skip this as an unknown packet
+    connection.write(Takeback::Supported)?;
+
+    let start = Fen::parse(fen.as_deref().unwrap_or(fen::STARTING_FEN))?;
+    Ok((Clock::from_time_control(time, inc), start))
+}
+
+/// Exchanges Start packets for a resumed game, then the moves each save holds. Both
+/// sides send the color from their save and the position the game started from as
+/// the FEN, and the game only goes on if the opponent resumes the same game with the
+/// other color. A move may be missing from one of the saves if the connection broke
+/// while it was sent, so both sides resume after the moves the saves have in common.
+/// The clocks are taken from the save, as Start packets only carry the time control.
+/// Returns the number of saved moves to resume after.
+fn resume_handshake(connection: &mut Connection, saved: &SavedGame) -> GameResult<usize> {
+    connection.local_name = saved.local_name.clone();
+    connection.local_color = saved.local_color;
+    let is_white = saved.local_color == Color::WHITE;
+    let (time, inc) = match &saved.clock {
+        Some(clock) => {
+            let (time, increment) = clock.time_control();
+            (Some(time.as_secs()), Some(increment.as_secs()))
+        }
+        None => (None, None),
+    };
+    let start = Start {
+        is_white,
+        name: Some(saved.local_name.clone()),
+        fen: Some(saved.start.to_string()),
+        time,
+        inc,
+    };
+
+    let packet = exchange(connection, start)?;
+    connection.opponent_name = packet.name.clone();
+    if packet.is_white == is_white {
+        return Err(GameError::CustomError(format!(
+            "Color mismatch: both saves are for {}",
+            if is_white { "white" } else { "black" }
+        )));
+    }
+    // Compare parsed positions, so peers may format their FEN differently
+    let opponent_start = packet
+        .fen
+        .as_deref()
+        .and_then(|fen| Fen::parse(fen).ok())
+        .map(|fen| fen.to_string());
+    if opponent_start != Some(saved.start.to_string()) {
+        return Err(GameError::CustomError(format!(
+            "Start position mismatch: the opponent's game started from {}, this one from {}",
+            packet.fen.as_deref().unwrap_or("the starting position"),
+            saved.start
+        )));
     }
-    println!(
-        "I am {:?} and {:?}",
-        connection.multiplayer_status, connection.local_color
-    );
 
-    // Make a Context.
-    let (mut ctx, event_loop) = ContextBuilder::new("Chess", "Cool Game Author")
-        .window_setup(
-            WindowSetup::default()
-                .title("Cool chess game")
-                .samples(ggez::conf::NumSamples::Four),
-        )
-        .window_mode(WindowMode::default().dimensions(
-            TILE_SIZE * 8.0 + GRID_X * 2.0,
-            TILE_SIZE * 8.0 + GRID_Y * 2.0,
-        ))
-        .add_resource_path("./resources")
-        .build()
-        .expect("aieee, could not create ggez context!");
+    let moves = ResumeMoves {
+        moves: saved
+            .moves
+            .iter()
+            .map(|&played| opponent::coordinate_move(&opponent::move_packet(played)))
+            .collect(),
+    };
+    let opponent_moves = exchange(connection, moves.clone())?;
+    // Peers running other clients skip this as an unknown packet
+    connection.write(Takeback::Supported)?;
 
-    // Create an instance of your event handler.
-    // Usually, you should provide it with the Context object to
-    // use when setting your game up.
-    let my_game = Chess::new(&mut ctx, connection)?;
+    let common = moves
+        .moves
+        .iter()
+        .zip(&opponent_moves.moves)
+        .take_while(|(ours, theirs)| ours == theirs)
+        .count();
+    if common < moves.moves.len().max(opponent_moves.moves.len()) {
+        println!(
+            "The saves differ after {} moves: this one has {}, the opponent's {}",
+            common,
+            moves.moves.len(),
+            opponent_moves.moves.len()
+        );
+    }
+    Ok(common)
+}
 
-    // Run!
-    event::run(ctx, event_loop, my_game);
+/// Sends `packet` and reads the opponent's packet of the same kind. The client writes
+/// first and the server answers.
+fn exchange<T>(connection: &mut Connection, packet: T) -> GameResult<T>
+where
+    T: Serialize + for<'a> Deserialize<'a> + std::fmt::Debug,
+{
+    match connection.multiplayer_status {
+        MultiplayerStatus::Client => {
+            connection.write(packet)?;
+            Ok(connection.read_block()?)
+        }
+        MultiplayerStatus::Server => {
+            let answer = connection.read_block()?;
+            connection.write(packet)?;
+            Ok(answer)
+        }
+    }
+}
+
+fn color_from_white(is_white: bool) -> Color {
+    match is_white {
+        true => Color::WHITE,
+        false => Color::BLACK,
+    }
+}
+
+/// Color of the local player in games against the computer
+fn choose_color(choice: ColorChoice) -> Color {
+    color_from_white(match choice {
+        ColorChoice::White => true,
+        ColorChoice::Black => false,
+        ColorChoice::Random => coin_flip(),
+    })
+}
+
+/// Random choice for players without a color preference
+fn coin_flip() -> bool {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .is_ok_and(|time| time.subsec_nanos() % 2 == 0)
 }
 
-struct PieceImages {
+pub struct PieceImages {
     white_pawn: graphics::Image,
     white_rook: graphics::Image,
     white_bishop: graphics::Image,
@@ -164,10 +551,33 @@ impl PieceImages {
 struct Drawables {
     selected_frame: graphics::Mesh,
     possible_move_dot: graphics::Mesh,
+    promotion_background: graphics::Mesh,
     white_turn: graphics::Text,
     black_turn: graphics::Text,
     white_checkmated: graphics::Text,
     black_checkmated: graphics::Text,
+    stalemate: graphics::Text,
+    threefold_repetition: graphics::Text,
+    fifty_move_rule: graphics::Text,
+    insufficient_material: graphics::Text,
+    awaiting_ack: graphics::Text,
+    white_resigned: graphics::Text,
+    black_resigned: graphics::Text,
+    white_timeout: graphics::Text,
+    black_timeout: graphics::Text,
+    draw_agreed: graphics::Text,
+    resign_button: Button,
+    offer_draw_button: Button,
+    draw_offer_pending: graphics::Text,
+    prompt_background: graphics::Mesh,
+    draw_offer_prompt: graphics::Text,
+    accept_button: Button,
+    decline_button: Button,
+    takeback_button: Button,
+    takeback_pending: graphics::Text,
+    takeback_prompt: graphics::Text,
+    move_list_background: graphics::Mesh,
+    fen_background: graphics::Mesh,
 }
 
 impl Drawables {
@@ -188,6 +598,18 @@ impl Drawables {
             PINK_COLOR,
         )?;
         let dot = graphics::Mesh::from_data(ctx, mb.build());
+        let mb = &mut graphics::MeshBuilder::new();
+        mb.rectangle(
+            graphics::DrawMode::fill(),
+            Rect::new(0.0, 0.0, TILE_SIZE, TILE_SIZE * 4.0),
+            graphics::Color::from_rgb(230, 230, 230),
+        )?;
+        mb.rectangle(
+            graphics::DrawMode::stroke(5.0),
+            Rect::new(0.0, 0.0, TILE_SIZE, TILE_SIZE * 4.0),
+            PINK_COLOR,
+        )?;
+        let promotion_background = graphics::Mesh::from_data(ctx, mb.build());
         let white_turn = graphics::Text::new("White's turn!")
             .set_layout(TextLayout::center())
             .set_scale(28.0)
@@ -204,15 +626,199 @@ impl Drawables {
             .set_layout(TextLayout::center())
             .set_scale(28.0)
             .clone();
+        let stalemate = graphics::Text::new("Stalemate, it's a draw!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let threefold_repetition = graphics::Text::new("Draw by threefold repetition!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let fifty_move_rule = graphics::Text::new("Draw by the fifty-move rule!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let insufficient_material = graphics::Text::new("Draw by insufficient material!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let awaiting_ack = graphics::Text::new("Waiting for opponent...")
+            .set_layout(TextLayout::center())
+            .set_scale(20.0)
+            .clone();
+        let white_resigned = graphics::Text::new("White resigned, black wins!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let black_resigned = graphics::Text::new("Black resigned, white wins!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let resign_button = Button::new(
+            ctx,
+            Rect::new(
+                GRID_X + TILE_SIZE * 6.5,
+                GRID_Y + TILE_SIZE * 8.0 + 25.0,
+                TILE_SIZE * 1.5,
+                50.0,
+            ),
+            "Resign (R)",
+        )?;
+        let white_timeout = graphics::Text::new("White ran out of time, black wins!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let black_timeout = graphics::Text::new("Black ran out of time, white wins!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let draw_agreed = graphics::Text::new("Draw agreed!")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let offer_draw_button = Button::new(
+            ctx,
+            Rect::new(
+                GRID_X,
+                GRID_Y + TILE_SIZE * 8.0 + 25.0,
+                TILE_SIZE * 1.5,
+                50.0,
+            ),
+            "Offer draw (D)",
+        )?;
+        let draw_offer_pending = graphics::Text::new("Draw offer will be sent with your move")
+            .set_layout(TextLayout::center())
+            .set_scale(20.0)
+            .clone();
+
+        // Modal prompt centered on the board
+        let mb = &mut graphics::MeshBuilder::new();
+        let prompt_rect = Rect::new(
+            GRID_X + TILE_SIZE * 2.0,
+            GRID_Y + TILE_SIZE * 3.0,
+            TILE_SIZE * 4.0,
+            TILE_SIZE * 2.0,
+        );
+        mb.rectangle(
+            graphics::DrawMode::fill(),
+            prompt_rect,
+            graphics::Color::from_rgb(40, 40, 40),
+        )?;
+        mb.rectangle(graphics::DrawMode::stroke(5.0), prompt_rect, PINK_COLOR)?;
+        let prompt_background = graphics::Mesh::from_data(ctx, mb.build());
+        let mb = &mut graphics::MeshBuilder::new();
+        mb.rectangle(
+            graphics::DrawMode::fill(),
+            Rect::new(PANEL_X, GRID_Y, PANEL_WIDTH - GRID_X / 4.0, TILE_SIZE * 8.0),
+            graphics::Color::from_rgb(40, 40, 40),
+        )?;
+        let move_list_background = graphics::Mesh::from_data(ctx, mb.build());
+        let mb = &mut graphics::MeshBuilder::new();
+        let fen_rect = Rect::new(
+            GRID_X + TILE_SIZE * 0.25,
+            GRID_Y + TILE_SIZE * 3.25,
+            TILE_SIZE * 7.5,
+            TILE_SIZE * 1.5,
+        );
+        mb.rectangle(
+            graphics::DrawMode::fill(),
+            fen_rect,
+            graphics::Color::from_rgb(40, 40, 40),
+        )?;
+        mb.rectangle(graphics::DrawMode::stroke(5.0), fen_rect, PINK_COLOR)?;
+        let fen_background = graphics::Mesh::from_data(ctx, mb.build());
+        let draw_offer_prompt = graphics::Text::new("Opponent offers a draw")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
+        let accept_button = Button::new(
+            ctx,
+            Rect::new(
+                GRID_X + TILE_SIZE * 2.25,
+                GRID_Y + TILE_SIZE * 4.1,
+                TILE_SIZE * 1.5,
+                50.0,
+            ),
+            "Accept (Y)",
+        )?;
+        let decline_button = Button::new(
+            ctx,
+            Rect::new(
+                GRID_X + TILE_SIZE * 4.25,
+                GRID_Y + TILE_SIZE * 4.1,
+                TILE_SIZE * 1.5,
+                50.0,
+            ),
+            "Decline (N)",
+        )?;
+        let takeback_button = Button::new(
+            ctx,
+            Rect::new(
+                GRID_X + TILE_SIZE * 6.5,
+                GRID_Y * 0.25,
+                TILE_SIZE * 1.5,
+                50.0,
+            ),
+            "Take back (U)",
+        )?;
+        let takeback_pending = graphics::Text::new("Takeback requested (U to withdraw)")
+            .set_layout(TextLayout::center())
+            .set_scale(20.0)
+            .clone();
+        let takeback_prompt = graphics::Text::new("Opponent asks for a takeback")
+            .set_layout(TextLayout::center())
+            .set_scale(28.0)
+            .clone();
         Ok(Drawables {
             selected_frame: frame,
             possible_move_dot: dot,
+            promotion_background,
             white_turn,
             black_turn,
             white_checkmated,
             black_checkmated,
+            stalemate,
+            threefold_repetition,
+            fifty_move_rule,
+            insufficient_material,
+            awaiting_ack,
+            white_resigned,
+            black_resigned,
+            white_timeout,
+            black_timeout,
+            draw_agreed,
+            resign_button,
+            offer_draw_button,
+            draw_offer_pending,
+            prompt_background,
+            draw_offer_prompt,
+            accept_button,
+            decline_button,
+            takeback_button,
+            takeback_pending,
+            takeback_prompt,
+            move_list_background,
+            fen_background,
         })
     }
+
+    fn ending_banner(&self, ending: Ending) -> &graphics::Text {
+        match ending {
+            Ending::Checkmate(Color::WHITE) => &self.white_checkmated,
+            Ending::Checkmate(_) => &self.black_checkmated,
+            Ending::Stalemate => &self.stalemate,
+            Ending::ThreefoldRepetition => &self.threefold_repetition,
+            Ending::FiftyMoveRule => &self.fifty_move_rule,
+            Ending::InsufficientMaterial => &self.insufficient_material,
+        }
+    }
+
+    fn turn_banner(&self, turn: Color) -> &graphics::Text {
+        match turn {
+            Color::WHITE => &self.white_turn,
+            _ => &self.black_turn,
+        }
+    }
 }
 
 struct Selected {
@@ -220,84 +826,798 @@ struct Selected {
     moves: Vec<Move>,
 }
 
+/// Ways a game can end
+#[derive(Clone, Copy, PartialEq)]
+enum GameOver {
+    /// The position on the board ended the game
+    Board(Ending),
+    /// The given color resigned
+    Resigned(Color),
+    /// A draw offer was accepted
+    DrawAgreed,
+    /// The given color ran out of time
+    Timeout(Color),
+}
+
+impl GameOver {
+    /// Value of the PGN Result tag
+    fn result(&self) -> &'static str {
+        match self {
+            GameOver::Board(ending) if ending.is_draw() => "1/2-1/2",
+            GameOver::DrawAgreed => "1/2-1/2",
+            GameOver::Board(Ending::Checkmate(Color::WHITE))
+            | GameOver::Resigned(Color::WHITE)
+            | GameOver::Timeout(Color::WHITE) => "0-1",
+            _ => "1-0",
+        }
+    }
+}
+
+/// Progress of the move handshake with the opponent
+enum MoveState {
+    /// No move of ours is in flight
+    Idle,
+    /// Our move has been sent and is shown provisionally until the opponent acks it
+    AwaitingAck {
+        from: GridPosition,
+        to: Move,
+        promotion: Option<PromotionPiece>,
+        offer_draw: bool,
+        sent_at: Instant,
+    },
+    /// The opponent's move carried a draw offer; its ack is held back until the local
+    /// player answers, and the answer is sent as the ack's end state
+    DrawOffered,
+    /// We asked to take back `moves` halfmoves and wait for the answer. A `cancelled`
+    /// request still gets one, as the opponent may have accepted it already, and
+    /// `sent_at` is when the request or its withdrawal was sent.
+    TakebackRequested {
+        moves: usize,
+        sent_at: Instant,
+        cancelled: bool,
+    },
+    /// The opponent asked to take back `moves` halfmoves and waits for the local player
+    TakebackOffered { moves: usize },
+}
+
 pub struct Chess {
     game: Game,
+    history: PositionHistory,
+    // None for untimed games
+    clock: Option<Clock>,
+    // Clock times from before each move, put back when the move is taken back
+    clock_history: Vec<(Duration, Duration)>,
     grid: Grid,
     piece_images: PieceImages,
     selected_piece: Option<Selected>,
     drawables: Drawables,
-    connection: Connection,
-    requested_move: Option<Move>,
+    opponent: Box<dyn Opponent>,
+    requested_move: Option<(Move, Option<PromotionPiece>)>,
+    // Target square of a pawn move waiting for the player to pick a promotion piece
+    promotion_target: Option<Move>,
+    move_state: MoveState,
+    ack_timeout: Duration,
+    message_text: Option<graphics::Text>,
+    disconnected: bool,
+    game_over: Option<GameOver>,
+    // Attach a draw offer to our next move
+    offer_draw: bool,
+    // First row shown in the move list, None to follow the latest move
+    move_list_scroll: Option<usize>,
+    // Where the game is saved as PGN, and when it started
+    pgn_dir: PathBuf,
+    started: SystemTime,
+    // Where F writes the position, stdout when None
+    fen_file: Option<PathBuf>,
+    show_fen: bool,
+    // Show the board with rank 1 at the top, as the black player sees it
+    flipped: bool,
+    // Where the game is saved for resuming, and when it last was
+    save_file: Option<PathBuf>,
+    saved_at: Instant,
 }
 
 impl Chess {
-    pub fn new(ctx: &mut Context, connection: Connection) -> GameResult<Chess> {
+    pub fn new(
+        ctx: &mut Context,
+        setup: GameSetup,
+        pgn_dir: PathBuf,
+        fen_file: Option<PathBuf>,
+    ) -> GameResult<Chess> {
+        let GameSetup {
+            opponent,
+            ack_timeout,
+            mut clock,
+            start,
+            moves,
+            save_file,
+            started,
+        } = setup;
+        let mut game = start.to_game();
+        let mut history = PositionHistory::new(&game, &start);
+        for played in moves {
+            if !rules::play_move(
+                &mut game,
+                &mut history,
+                played.from,
+                played.to,
+                played.promotion,
+            ) {
+                return Err(GameError::CustomError(
+                    "Saved move could not be applied".to_string(),
+                ));
+            }
+        }
+        if let Some(clock) = &mut clock {
+            clock.start(game.turn);
+        }
+        // Saves only keep the current times, so taking back moves played before the
+        // game was resumed puts back those
+        let clock_history = match &clock {
+            Some(clock) => vec![clock.times(); history.moves.len()],
+            None => Vec::new(),
+        };
+        // The black player sees the board from their side
+        let flipped = opponent.local_color() == Some(Color::BLACK);
         Ok(Chess {
             // ...
-            game: Game::new(Some("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR".into())),
+            history,
+            clock,
+            clock_history,
+            game,
             grid: Grid::new(ctx)?,
             piece_images: PieceImages::new(ctx)?,
             selected_piece: None,
             drawables: Drawables::new(ctx)?,
-            connection,
+            opponent,
             requested_move: None,
+            promotion_target: None,
+            move_state: MoveState::Idle,
+            ack_timeout,
+            message_text: None,
+            disconnected: false,
+            game_over: None,
+            offer_draw: false,
+            move_list_scroll: None,
+            pgn_dir,
+            started,
+            fen_file,
+            show_fen: false,
+            flipped,
+            save_file,
+            saved_at: Instant::now(),
         })
     }
+
+    fn show_error(&mut self, message: &str) {
+        println!("Error: {}", message);
+        self.show_message(message);
+    }
+
+    /// Shows a line below the board until the next move
+    fn show_message(&mut self, message: &str) {
+        self.message_text = Some(
+            graphics::Text::new(message)
+                .set_layout(TextLayout::center())
+                .set_scale(20.0)
+                .clone(),
+        );
+    }
+
+    /// Gives up the game, telling the opponent through a forfeiting move. In local
+    /// games the side to move resigns.
+    fn resign(&mut self) {
+        if self.game_over.is_some() {
+            return;
+        }
+        let forfeit = opponent::forfeit();
+        self.send(|opponent| opponent.send_move(forfeit));
+        let color = self.opponent.local_color().unwrap_or(self.game.turn);
+        self.end_game(GameOver::Resigned(color));
+    }
+
+    /// Toggles the draw offer attached to our next move. In local games the offer is
+    /// put to the other player right away.
+    fn toggle_draw_offer(&mut self) {
+        if self.game_over.is_some() {
+            return;
+        }
+        match self.opponent.local_color() {
+            Some(_) => self.offer_draw = !self.offer_draw,
+            None if matches!(self.move_state, MoveState::Idle) => {
+                self.move_state = MoveState::DrawOffered
+            }
+            None => {}
+        }
+    }
+
+    /// Answers the opponent's pending draw offer
+    fn answer_draw_offer(&mut self, accept: bool) {
+        if !matches!(self.move_state, MoveState::DrawOffered) {
+            return;
+        }
+        self.move_state = MoveState::Idle;
+        let sent = self.send(|opponent| {
+            opponent.send_ack(chess_networking::Ack {
+                ok: true,
+                end_state: accept.then_some(GameState::Draw),
+            })
+        });
+        if sent && accept {
+            self.end_game(GameOver::DrawAgreed);
+        }
+    }
+
+    /// Asks to take back moves until it is our turn again, or withdraws the request if
+    /// one is pending. Local opponents grant this right away, networked ones have to
+    /// accept it first.
+    fn request_takeback(&mut self) {
+        if let MoveState::TakebackRequested { .. } = self.move_state {
+            self.cancel_takeback();
+            return;
+        }
+        if self.game_over.is_some() || !matches!(self.move_state, MoveState::Idle) {
+            return;
+        }
+        if !self.opponent.supports_takebacks() {
+            self.show_error("Opponent's client does not support takebacks");
+            return;
+        }
+        // Only our last move if the opponent has not answered it yet, else their answer too
+        let moves = match self.opponent.local_color() {
+            Some(color) if color == self.game.turn => 2,
+            _ => 1,
+        };
+        if moves > self.history.moves.len() {
+            self.show_error("No move to take back");
+            return;
+        }
+        if !self.send(|opponent| opponent.send_takeback(Takeback::Request { moves: moves as u8 })) {
+            return;
+        }
+        self.move_state = MoveState::TakebackRequested {
+            moves,
+            sent_at: Instant::now(),
+            cancelled: false,
+        };
+        self.selected_piece = None;
+        self.promotion_target = None;
+        self.requested_move = None;
+    }
+
+    /// Withdraws our takeback request. The board stays locked until the opponent has
+    /// answered it, so both sides agree on whether the moves were taken back.
+    fn cancel_takeback(&mut self) {
+        let MoveState::TakebackRequested {
+            moves,
+            cancelled: false,
+            ..
+        } = self.move_state
+        else {
+            return;
+        };
+        if self.send(|opponent| opponent.send_takeback(Takeback::Cancel)) {
+            self.move_state = MoveState::TakebackRequested {
+                moves,
+                sent_at: Instant::now(),
+                cancelled: true,
+            };
+            self.show_message("Withdrawing the takeback request");
+        }
+    }
+
+    /// Answers the opponent's pending takeback request
+    fn answer_takeback(&mut self, accept: bool) {
+        let MoveState::TakebackOffered { moves } = self.move_state else {
+            return;
+        };
+        self.move_state = MoveState::Idle;
+        let answer = match accept {
+            true => Takeback::Accept,
+            false => Takeback::Decline,
+        };
+        if self.send(|opponent| opponent.send_takeback(answer)) && accept {
+            self.take_back(moves);
+        }
+    }
+
+    /// Answers whichever question the opponent has put to the local player
+    fn answer_prompt(&mut self, accept: bool) {
+        match self.move_state {
+            MoveState::DrawOffered => self.answer_draw_offer(accept),
+            MoveState::TakebackOffered { .. } => self.answer_takeback(accept),
+            _ => {}
+        }
+    }
+
+    /// Whether the opponent may ask to take back `moves` halfmoves: there have to be
+    /// as many, and afterwards it has to be their turn
+    fn grants_takeback(&self, moves: u8) -> bool {
+        let turn_after = match moves % 2 {
+            1 => rules::opponent(self.game.turn),
+            _ => self.game.turn,
+        };
+        (1..=2).contains(&moves)
+            && moves as usize <= self.history.moves.len()
+            && Some(turn_after) != self.opponent.local_color()
+    }
+
+    /// Rolls the board and the clock back, and gives the clock to whoever is to move
+    /// afterwards
+    fn take_back(&mut self, moves: usize) {
+        if !rules::take_back(&mut self.game, &mut self.history, moves) {
+            self.show_error("Could not take back the moves");
+            return;
+        }
+        self.message_text = None;
+        self.selected_piece = None;
+        self.promotion_target = None;
+        self.requested_move = None;
+        let kept = self.history.moves.len();
+        if let Some(clock) = &mut self.clock {
+            match self.clock_history.get(kept) {
+                Some(&times) => clock.restore(times, self.game.turn),
+                None => clock.start(self.game.turn),
+            }
+        }
+        self.clock_history.truncate(kept);
+        self.save_game();
+    }
+
+    /// First move list row to show when the list has `rows` rows
+    fn move_list_top(&self, rows: usize) -> usize {
+        let last_page = rows.saturating_sub(MOVE_LIST_ROWS);
+        self.move_list_scroll
+            .map_or(last_page, |top| top.min(last_page))
+    }
+
+    /// Name of the player of `color` for the PGN tags, "?" if unknown
+    fn player_name(&self, color: Color) -> String {
+        let name = match self.opponent.local_color() {
+            Some(local_color) if local_color == color => self.opponent.local_name(),
+            Some(_) => self.opponent.name(),
+            None => None,
+        };
+        name.unwrap_or("?").to_string()
+    }
+
+    /// The game so far in PGN, with the result left open while it is being played
+    fn pgn(&self) -> Pgn {
+        let time_control = match &self.clock {
+            Some(clock) => match clock.time_control() {
+                (time, Duration::ZERO) => time.as_secs().to_string(),
+                (time, increment) => format!("{}+{}", time.as_secs(), increment.as_secs()),
+            },
+            None => "-".to_string(),
+        };
+        let mut tags = vec![
+            ("Event", "Casual game".to_string()),
+            ("Site", "?".to_string()),
+            ("Date", pgn::date(self.started)),
+            ("Round", "-".to_string()),
+            ("White", self.player_name(Color::WHITE)),
+            ("Black", self.player_name(Color::BLACK)),
+            (
+                "Result",
+                self.game_over.map_or("*", |over| over.result()).to_string(),
+            ),
+            ("TimeControl", time_control),
+        ];
+        let start = self.history.start.to_string();
+        if start != fen::STARTING_FEN {
+            tags.push(("SetUp", "1".to_string()));
+            tags.push(("FEN", start));
+        }
+        Pgn {
+            tags: tags
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            moves: self.history.san.clone(),
+        }
+    }
+
+    /// Writes the game to the PGN directory, replacing what earlier saves of the same
+    /// game wrote. Only failures are shown unless `announce`.
+    fn save_pgn(&mut self, announce: bool) {
+        let file_name = pgn::file_name(
+            self.started,
+            &self.player_name(Color::WHITE),
+            &self.player_name(Color::BLACK),
+        );
+        let path = self.pgn_dir.join(file_name);
+        match self.pgn().save(&path) {
+            Ok(()) => {
+                println!("Saved game to {}", path.display());
+                if announce {
+                    self.show_message(&format!("Saved game to {}", path.display()));
+                }
+            }
+            Err(e) => self.show_error(&format!("Could not save {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Shows or hides the FEN of the current position, writing it out when shown
+    fn toggle_fen(&mut self) {
+        self.show_fen = !self.show_fen;
+        if !self.show_fen {
+            return;
+        }
+        let fen = self.history.fen(&self.game);
+        match (fen::export(&fen, self.fen_file.as_deref()), &self.fen_file) {
+            (Ok(()), Some(file)) => self.show_message(&format!("Wrote FEN to {}", file.display())),
+            (Ok(()), None) => {}
+            (Err(e), _) => self.show_error(&format!("Could not write FEN: {}", e)),
+        }
+    }
+
+    /// Writes the save a resumed game starts from. Once the game is over the save
+    /// records the result, and resuming it is refused.
+    fn save_game(&mut self) {
+        self.saved_at = Instant::now();
+        let (Some(save_file), Some(local_color)) =
+            (self.save_file.clone(), self.opponent.local_color())
+        else {
+            return;
+        };
+        if let Err(e) = save::write(&save_file, self.pgn(), local_color, self.clock.as_ref()) {
+            self.show_error(&format!("Could not save {}: {}", save_file.display(), e));
+        }
+    }
+
+    /// Sends a packet to the opponent, returning whether it went out. A failed send
+    /// means the opponent is gone, which loses the connection instead of ending the
+    /// program.
+    fn send(&mut self, send: impl FnOnce(&mut dyn Opponent) -> GameResult) -> bool {
+        if self.disconnected {
+            return false;
+        }
+        match send(self.opponent.as_mut()) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Could not send: {:?}", e);
+                self.lose_connection("Could not reach the opponent");
+                false
+            }
+        }
+    }
+
+    /// Stops talking to the opponent, dropping whatever was being negotiated. The
+    /// window stays open, and networked games can be resumed from their save.
+    fn lose_connection(&mut self, message: &str) {
+        self.disconnected = true;
+        self.move_state = MoveState::Idle;
+        self.selected_piece = None;
+        self.promotion_target = None;
+        self.requested_move = None;
+        self.show_error(message);
+    }
+
+    fn end_game(&mut self, game_over: GameOver) {
+        self.game_over = Some(game_over);
+        if let Some(clock) = &mut self.clock {
+            clock.stop();
+        }
+        self.move_state = MoveState::Idle;
+        self.selected_piece = None;
+        self.promotion_target = None;
+        self.requested_move = None;
+        self.offer_draw = false;
+        self.save_pgn(false);
+        self.save_game();
+    }
+
+    /// Reacts to a packet from the opponent according to the current handshake state
+    fn handle_message(&mut self, message: Message) {
+        if self.game_over.is_some() {
+            // Nothing left to negotiate, e.g. the ack for our forfeit
+            println!("Ignoring packet after game end: {:?}", message);
+            return;
+        }
+        match (message, &self.move_state) {
+            (Message::Move(mov), _) if mov.forfeit => {
+                self.send(|opponent| {
+                    opponent.send_ack(chess_networking::Ack {
+                        ok: true,
+                        end_state: None,
+                    })
+                });
+                let opponent =
+                    rules::opponent(self.opponent.local_color().unwrap_or(self.game.turn));
+                self.end_game(GameOver::Resigned(opponent));
+            }
+            (Message::Ack(ack), MoveState::AwaitingAck { .. }) => self.handle_ack(ack),
+            (Message::Move(mov), MoveState::Idle) => self.handle_move(mov),
+            (Message::Move(mov), MoveState::TakebackRequested { .. }) => {
+                // The opponent moved before seeing our request, they will decline it
+                self.move_state = MoveState::Idle;
+                self.handle_move(mov);
+            }
+            (Message::Takeback(takeback), _) => self.handle_takeback(takeback),
+            (Message::Move(mov), _) => {
+                // Opponent moved while our own move is in flight
+                self.show_error("Unexpected move from opponent while awaiting ack");
+                println!("Rejecting move: {:?}", mov);
+                self.send(|opponent| {
+                    opponent.send_ack(chess_networking::Ack {
+                        ok: false,
+                        end_state: None,
+                    })
+                });
+            }
+            (message, _) => {
+                self.show_error("Unexpected packet from opponent");
+                println!("Ignoring packet: {:?}", message);
+            }
+        }
+    }
+
+    /// Reacts to a takeback request or to the answer to ours
+    fn handle_takeback(&mut self, takeback: Takeback) {
+        match (takeback, &self.move_state) {
+            (Takeback::Request { moves }, MoveState::Idle) if self.grants_takeback(moves) => {
+                self.move_state = MoveState::TakebackOffered {
+                    moves: moves as usize,
+                };
+            }
+            (Takeback::Request { moves }, _) => {
+                // Crossed a move of ours, or asks for something we cannot take back
+                println!("Declining takeback of {} moves", moves);
+                self.send(|opponent| opponent.send_takeback(Takeback::Decline));
+            }
+            (Takeback::Accept, &MoveState::TakebackRequested { moves, .. }) => {
+                self.move_state = MoveState::Idle;
+                self.take_back(moves);
+            }
+            (Takeback::Decline, &MoveState::TakebackRequested { cancelled, .. }) => {
+                self.move_state = MoveState::Idle;
+                match cancelled {
+                    true => self.message_text = None,
+                    false => self.show_error("Opponent declined the takeback"),
+                }
+            }
+            // Connection::poll already noted that the opponent supports takebacks
+            (Takeback::Supported, _) => {}
+            (Takeback::Cancel, MoveState::TakebackOffered { .. }) => {
+                self.move_state = MoveState::Idle;
+                self.send(|opponent| opponent.send_takeback(Takeback::Decline));
+                self.show_message("Opponent withdrew the takeback request");
+            }
+            (answer, _) => println!("Ignoring takeback answer: {:?}", answer),
+        }
+    }
+
+    /// Applies our in-flight move once acked, dropping it when rejected
+    fn handle_ack(&mut self, ack: chess_networking::Ack) {
+        let MoveState::AwaitingAck {
+            from,
+            to,
+            promotion,
+            offer_draw,
+            ..
+        } = self.move_state
+        else {
+            return;
+        };
+        self.move_state = MoveState::Idle;
+
+        if !ack.ok {
+            self.show_error("Opponent rejected the move");
+            return;
+        }
+        if !self.play_move(from, to, promotion) {
+            self.show_error("Acked move could not be applied");
+            return;
+        }
+
+        let ending = rules::ending(&self.game, &self.history);
+        match (ending, ack.end_state) {
+            (None, Some(GameState::Draw)) if offer_draw => self.end_game(GameOver::DrawAgreed),
+            (None, None) if offer_draw => self.show_error("Opponent declined the draw offer"),
+            (ending, reported) => {
+                // Our own rules decide, a disagreeing peer is only reported
+                if reported != ending.map(end_state) {
+                    println!("Opponent reported {:?}, expected {:?}", reported, ending);
+                    self.show_error("Opponent reports a different game result");
+                }
+                if let Some(ending) = ending {
+                    self.end_game(GameOver::Board(ending));
+                }
+            }
+        }
+    }
+
+    /// Verifies and acks (or rejects) a move from the opponent
+    fn handle_move(&mut self, mov: chess_networking::Move) {
+        let ok = self.apply_remote_move(&mov);
+        let ending = match ok {
+            true => rules::ending(&self.game, &self.history),
+            false => None,
+        };
+        if !ok {
+            println!("Rejecting illegal move: {:?}", mov);
+        } else if mov.offer_draw && ending.is_none() {
+            // Ack is sent once the player accepts or declines
+            self.move_state = MoveState::DrawOffered;
+            return;
+        }
+        self.send(|opponent| {
+            opponent.send_ack(chess_networking::Ack {
+                ok,
+                end_state: ending.map(end_state),
+            })
+        });
+        if let Some(ending) = ending {
+            self.end_game(GameOver::Board(ending));
+        }
+    }
+
+    /// Verifies a move received from the opponent and applies it if it is legal.
+    /// Returns whether the move was accepted.
+    fn apply_remote_move(&mut self, mov: &chess_networking::Move) -> bool {
+        // Only accept moves when it is actually the opponent's turn
+        if Some(self.game.turn) == self.opponent.local_color() {
+            return false;
+        }
+        if mov.from.0 > 7 || mov.from.1 > 7 || mov.to.0 > 7 || mov.to.1 > 7 {
+            return false;
+        }
+
+        let (from_x, from_y) = (mov.from.0 as i32, 7 - mov.from.1 as i32); // Adjust y
+        let target = Move(mov.to.0 as i32, 7 - mov.to.1 as i32); // Adjust y
+
+        let piece = self.game.board.pieces[from_y as usize][from_x as usize];
+        if piece.piece_type == PieceType::EMPTY || piece.color != self.game.turn {
+            return false;
+        }
+        if !rules::legal_moves(&self.game, &self.history, from_x, from_y).contains(&target) {
+            return false;
+        }
+
+        // Peers that leave out the promotion piece get the customary queen
+        let promotion = match is_promotion(piece.piece_type, target) {
+            true => Some(mov.promotion.unwrap_or(PromotionPiece::Queen)),
+            false => None,
+        };
+        let from = (from_x as usize, from_y as usize).into();
+        self.play_move(from, target, promotion)
+    }
+
+    /// Plays a move made in a local game and ends the game if it is over
+    fn play_local_move(&mut self, from: GridPosition, to: Move, promotion: Option<PromotionPiece>) {
+        if !self.play_move(from, to, promotion) {
+            self.show_error("Move could not be applied");
+            return;
+        }
+        if let Some(ending) = rules::ending(&self.game, &self.history) {
+            self.end_game(GameOver::Board(ending));
+        }
+    }
+
+    /// Plays a legal move and hands the turn over on the clock. Returns whether
+    /// chess_lib accepted the move.
+    fn play_move(
+        &mut self,
+        from: GridPosition,
+        to: Move,
+        promotion: Option<PromotionPiece>,
+    ) -> bool {
+        let times = self.clock.as_ref().map(Clock::times);
+        if !rules::play_move(&mut self.game, &mut self.history, from, to, promotion) {
+            return false;
+        }
+        if let (Some(clock), Some(times)) = (&mut self.clock, times) {
+            self.clock_history.push(times);
+            clock.complete_move(self.game.turn);
+        }
+        // Both sides save right away, so their saves agree on the moves
+        self.save_game();
+        true
+    }
+}
+
+/// Protocol representation of a game ending
+pub fn end_state(ending: Ending) -> GameState {
+    match ending.is_draw() {
+        true => GameState::Draw,
+        false => GameState::CheckMate,
+    }
+}
+
+pub fn is_promotion(piece_type: PieceType, target: Move) -> bool {
+    piece_type == PieceType::PAWN && (target.1 == 0 || target.1 == 7)
+}
+
+pub fn promotion_piece_type(promotion: PromotionPiece) -> PieceType {
+    match promotion {
+        PromotionPiece::Queen => PieceType::QUEEN,
+        PromotionPiece::Rook => PieceType::ROOK,
+        PromotionPiece::Bishop => PieceType::BISHOP,
+        PromotionPiece::Knight => PieceType::KNIGHT,
+    }
+}
+
+/// Replaces the pawn that just reached `square` with the chosen piece
+pub fn promote(game: &mut Game, square: Move, promotion: PromotionPiece) {
+    game.board.pieces[square.1 as usize][square.0 as usize].piece_type =
+        promotion_piece_type(promotion);
 }
 
-pub fn board2grid(x: usize, y: usize) -> Vec2 {
+/// Board row of the `index`th square of the promotion chooser for a pawn promoting on `target`
+pub fn promotion_row(target: Move, index: usize) -> usize {
+    if target.1 == 0 {
+        index
+    } else {
+        7 - index
+    }
+}
+
+/// Converts window pixel coordinates to the fixed drawing coordinates
+pub fn window2screen(ctx: &Context, x: f32, y: f32) -> (f32, f32) {
+    let (width, height) = ctx.gfx.drawable_size();
+    (x * WINDOW_WIDTH / width, y * WINDOW_HEIGHT / height)
+}
+
+/// Screen position of the top left corner of square (x, y), with rank 1 at the top
+/// if `flipped`
+pub fn board2grid(x: usize, y: usize, flipped: bool) -> Vec2 {
+    let (x, y) = match flipped {
+        true => (7 - x, 7 - y),
+        false => (x, y),
+    };
     vec2(GRID_X + x as f32 * TILE_SIZE, GRID_Y + y as f32 * TILE_SIZE)
 }
 
+/// Draws a piece on the square whose top left corner is at `position`
 pub fn draw_piece(
-    chess: &mut Chess,
+    images: &PieceImages,
     canvas: &mut Canvas,
-    x: usize,
-    y: usize,
+    position: Vec2,
     piece_type: PieceType,
     color: Color,
+    opacity: f32,
 ) {
     let image = match piece_type {
         PieceType::PAWN => {
             if color == Color::WHITE {
-                &chess.piece_images.white_pawn
+                &images.white_pawn
             } else {
-                &chess.piece_images.black_pawn
+                &images.black_pawn
             }
         }
         PieceType::ROOK => {
             if color == Color::WHITE {
-                &chess.piece_images.white_rook
+                &images.white_rook
             } else {
-                &chess.piece_images.black_rook
+                &images.black_rook
             }
         }
         PieceType::BISHOP => {
             if color == Color::WHITE {
-                &chess.piece_images.white_bishop
+                &images.white_bishop
             } else {
-                &chess.piece_images.black_bishop
+                &images.black_bishop
             }
         }
         PieceType::KNIGHT => {
             if color == Color::WHITE {
-                &chess.piece_images.white_knight
+                &images.white_knight
             } else {
-                &chess.piece_images.black_knight
+                &images.black_knight
             }
         }
         PieceType::QUEEN => {
             if color == Color::WHITE {
-                &chess.piece_images.white_queen
+                &images.white_queen
             } else {
-                &chess.piece_images.black_queen
+                &images.black_queen
             }
         }
         PieceType::KING => {
             if color == Color::WHITE {
-                &chess.piece_images.white_king
+                &images.white_king
             } else {
-                &chess.piece_images.black_king
+                &images.black_king
             }
         }
         PieceType::EMPTY => panic!("Bruh"),
@@ -307,67 +1627,208 @@ pub fn draw_piece(
         TILE_SIZE / image.height() as f32, // Scale width the same as height
         TILE_SIZE / image.height() as f32,
     );
-    let position = board2grid(x, y);
     canvas.draw(
         image,
         DrawParam::new()
             .dest(position + TILE_SIZE * 0.5)
             .scale(scale * 0.8)
-            .offset(vec2(0.5, 0.5)),
+            .offset(vec2(0.5, 0.5))
+            .color(graphics::Color::new(1.0, 1.0, 1.0, opacity)),
     );
 }
 
+/// Colors whose pieces start at the top and at the bottom of the board
+pub fn sides(flipped: bool) -> (Color, Color) {
+    match flipped {
+        true => (Color::WHITE, Color::BLACK),
+        false => (Color::BLACK, Color::WHITE),
+    }
+}
+
+/// Labels the files along the bottom edge of the board and the ranks along the left
+pub fn draw_coordinates(canvas: &mut Canvas, flipped: bool) {
+    for i in 0..8 {
+        let square = match flipped {
+            true => 7 - i,
+            false => i,
+        };
+        let file = graphics::Text::new(((b'a' + square as u8) as char).to_string())
+            .set_layout(TextLayout {
+                h_align: graphics::TextAlign::End,
+                v_align: graphics::TextAlign::End,
+            })
+            .set_scale(16.0)
+            .clone();
+        let corner = vec2((i + 1) as f32, 8.0) * TILE_SIZE - 4.0;
+        canvas.draw(
+            &file,
+            DrawParam::new()
+                .dest(vec2(GRID_X, GRID_Y) + corner)
+                .color(PINK_COLOR),
+        );
+        let rank = graphics::Text::new(((b'8' - square as u8) as char).to_string())
+            .set_layout(TextLayout {
+                h_align: graphics::TextAlign::Begin,
+                v_align: graphics::TextAlign::Begin,
+            })
+            .set_scale(16.0)
+            .clone();
+        let corner = vec2(0.0, i as f32) * TILE_SIZE + 4.0;
+        canvas.draw(
+            &rank,
+            DrawParam::new()
+                .dest(vec2(GRID_X, GRID_Y) + corner)
+                .color(PINK_COLOR),
+        );
+    }
+}
+
+/// Shows `fen` in a box over the middle of the board
+fn draw_fen(canvas: &mut Canvas, drawables: &Drawables, fen: &Fen) {
+    canvas.draw(&drawables.fen_background, vec2(0.0, 0.0));
+    let text = graphics::Text::new(format!("{}\n\nPress F to close", fen))
+        .set_layout(TextLayout::center())
+        .set_scale(20.0)
+        .clone();
+    canvas.draw(
+        &text,
+        vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y + TILE_SIZE * 4.0),
+    );
+}
+
+/// Draws the rows of the move list from `top` on, the `current` one highlighted
+pub fn draw_move_list(canvas: &mut Canvas, rows: &[String], top: usize, current: Option<usize>) {
+    for (i, row) in rows.iter().enumerate().skip(top).take(MOVE_LIST_ROWS) {
+        let text = graphics::Text::new(row.as_str())
+            .set_layout(TextLayout {
+                h_align: graphics::TextAlign::Begin,
+                v_align: graphics::TextAlign::Begin,
+            })
+            .set_scale(20.0)
+            .clone();
+        let text_color = match current == Some(i) {
+            true => PINK_COLOR,
+            false => WHITE_COLOR,
+        };
+        let y = GRID_Y + 10.0 + (i - top) as f32 * MOVE_LIST_ROW_HEIGHT;
+        canvas.draw(
+            &text,
+            DrawParam::new()
+                .dest(vec2(PANEL_X + 10.0, y))
+                .color(text_color),
+        );
+    }
+}
+
 impl EventHandler for Chess {
     fn update(&mut self, _ctx: &mut Context) -> GameResult {
         // Update code here...
-        if let Some(mov) = self.requested_move {
-            let selected = self.selected_piece.as_ref().unwrap();
-            // Send move request, wait for Ack packet
-            self.connection.write(chess_networking::Move {
+        if let Some((mov, promotion)) = self.requested_move.take() {
+            let Some(selected) = self.selected_piece.take() else {
+                return Ok(());
+            };
+            if self.opponent.local_color().is_none() {
+                // Local game, nobody to ask
+                self.play_local_move(selected.position, mov, promotion);
+                return Ok(());
+            }
+
+            let offer_draw = std::mem::take(&mut self.offer_draw);
+            // Send move request, the Ack is picked up by handle_message in later updates
+            let packet = chess_networking::Move {
                 from: (selected.position.x as u8, 7 - selected.position.y as u8), // Adjust y
                 to: (mov.0 as u8, 7 - mov.1 as u8),                               // Adjust y
-                promotion: None,
+                promotion,
                 forfeit: false,
-                offer_draw: false,
-            })?;
-            let ack: chess_networking::Ack = self.connection.read_block()?;
-            if ack.ok {
-                move_piece(
-                    mov,
-                    selected.position.x as i32,
-                    selected.position.y as i32,
-                    &mut self.game,
-                )
-                .unwrap();
+                offer_draw,
+            };
+            if self.send(|opponent| opponent.send_move(packet)) {
+                self.message_text = None;
+                self.move_state = MoveState::AwaitingAck {
+                    from: selected.position,
+                    to: mov,
+                    promotion,
+                    offer_draw,
+                    sent_at: Instant::now(),
+                };
+            }
+        }
+
+        if !self.disconnected {
+            match self.opponent.poll() {
+                Ok(Some(message)) => self.handle_message(message),
+                Ok(None) | Err(ReadError::Incomplete) => {}
+                Err(ReadError::Disconnected) => self.lose_connection("Opponent disconnected"),
+                Err(ReadError::Unknown) => self.show_error("Skipped unknown packet from opponent"),
+                // A peer sending garbage or dropping the connection must not end the program
+                Err(ReadError::Decode(e)) => {
+                    println!("Could not decode packet: {}", e);
+                    self.lose_connection("Unreadable packet from opponent");
+                }
+                Err(ReadError::IO(e)) => {
+                    println!("Could not read from opponent: {}", e);
+                    self.lose_connection("Lost the connection to the opponent");
+                }
             }
-            self.selected_piece = None;
-            self.requested_move = None;
         }
 
-        let mov = self.connection.read::<chess_networking::Move>();
-        if let Ok(mov) = mov {
-            // Received move request, verify move and then send ack and create move locally
-            // Verify here
+        if self.game_over.is_none() && self.saved_at.elapsed() > SAVE_INTERVAL {
+            self.save_game();
+        }
 
-            self.connection.write(chess_networking::Ack {
-                ok: true,
-                end_state: None,
-            })?;
-            move_piece(
-                Move(mov.to.0 as i32, 7 - mov.to.1 as i32), // Adjust y
-                mov.from.0 as i32,
-                7 - mov.from.1 as i32, // Adjust y
-                &mut self.game,
-            )
-            .unwrap();
+        if let Some(color) = self.clock.as_ref().and_then(|clock| clock.flagged()) {
+            if self.game_over.is_none() {
+                self.end_game(GameOver::Timeout(color));
+            }
+        }
+
+        // A draw offer waits on the opponent deciding, so it is not subject to the timeout
+        if let MoveState::AwaitingAck {
+            sent_at,
+            offer_draw: false,
+            ..
+        } = self.move_state
+        {
+            if sent_at.elapsed() > self.ack_timeout {
+                // The opponent may have played the move already, so a late ack could
+                // only leave the boards out of sync
+                self.lose_connection("Opponent did not acknowledge the move in time");
+            }
+        }
+
+        // The opponent may take as long as they like to decide on a takeback, the
+        // local player withdraws the request with U. Their client answers the withdrawal
+        // by itself, so that answer is waited for like an ack.
+        if let MoveState::TakebackRequested {
+            sent_at,
+            cancelled: true,
+            ..
+        } = self.move_state
+        {
+            if sent_at.elapsed() > self.ack_timeout {
+                self.lose_connection("Opponent did not answer the takeback request");
+            }
         }
         Ok(())
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         let mut canvas = graphics::Canvas::from_frame(ctx, graphics::Color::BLACK);
+        canvas.set_screen_coordinates(Rect::new(0.0, 0.0, WINDOW_WIDTH, WINDOW_HEIGHT));
         canvas.set_sampler(graphics::Sampler::linear_clamp());
         canvas.draw(&self.grid.mesh, vec2(GRID_X, GRID_Y));
+        draw_coordinates(&mut canvas, self.flipped);
+
+        // Pending move is drawn faded on its target square until it is acked
+        let provisional = match self.move_state {
+            MoveState::AwaitingAck {
+                from,
+                to,
+                promotion,
+                ..
+            } => Some((from, to, promotion)),
+            _ => None,
+        };
 
         for y in 0..8 {
             for x in 0..8 {
@@ -375,36 +1836,176 @@ impl EventHandler for Chess {
                 if piece.color == Color::EMPTY {
                     continue;
                 }
-                draw_piece(self, &mut canvas, x, y, piece.piece_type, piece.color);
+                if let Some((from, to, _)) = provisional {
+                    if from == (x, y).into() || (to.0 as usize, to.1 as usize) == (x, y) {
+                        continue;
+                    }
+                }
+                draw_piece(
+                    &self.piece_images,
+                    &mut canvas,
+                    board2grid(x, y, self.flipped),
+                    piece.piece_type,
+                    piece.color,
+                    1.0,
+                );
             }
         }
 
+        if let Some((from, to, promotion)) = provisional {
+            let piece = self.game.board.pieces[from.y][from.x];
+            let piece_type = promotion.map_or(piece.piece_type, promotion_piece_type);
+            let (x, y) = (to.0 as usize, to.1 as usize);
+            draw_piece(
+                &self.piece_images,
+                &mut canvas,
+                board2grid(x, y, self.flipped),
+                piece_type,
+                piece.color,
+                0.5,
+            );
+        }
+
         if let Some(selected) = &self.selected_piece {
-            let pos = board2grid(selected.position.x, selected.position.y);
+            let pos = board2grid(selected.position.x, selected.position.y, self.flipped);
             canvas.draw(&self.drawables.selected_frame, pos);
 
             for grid_pos in &selected.moves {
-                let pos = board2grid(grid_pos.0 as usize, grid_pos.1 as usize);
+                let pos = board2grid(grid_pos.0 as usize, grid_pos.1 as usize, self.flipped);
                 canvas.draw(&self.drawables.possible_move_dot, pos);
             }
         }
 
-        let text = match (
-            self.game.turn,
-            self.game.check_mate_white,
-            self.game.check_mate_black,
-        ) {
-            (_, true, false) => &self.drawables.white_checkmated,
-            (_, false, true) => &self.drawables.black_checkmated,
-            (Color::WHITE, _, _) => &self.drawables.white_turn,
-            (Color::BLACK, _, _) => &self.drawables.black_turn,
-            _ => panic!("Bruh???"),
+        if let Some(target) = self.promotion_target {
+            // The chooser runs from the target square towards the middle of the board
+            let x = target.0 as usize;
+            let corner = board2grid(x, promotion_row(target, 0), self.flipped).min(board2grid(
+                x,
+                promotion_row(target, 3),
+                self.flipped,
+            ));
+            canvas.draw(&self.drawables.promotion_background, corner);
+            let color = self.game.turn;
+            for (i, &choice) in PROMOTION_CHOICES.iter().enumerate() {
+                let y = promotion_row(target, i);
+                let piece_type = promotion_piece_type(choice);
+                draw_piece(
+                    &self.piece_images,
+                    &mut canvas,
+                    board2grid(x, y, self.flipped),
+                    piece_type,
+                    color,
+                    1.0,
+                );
+            }
+        }
+
+        let text = match self.game_over {
+            Some(GameOver::Board(ending)) => self.drawables.ending_banner(ending),
+            Some(GameOver::Resigned(Color::WHITE)) => &self.drawables.white_resigned,
+            Some(GameOver::Resigned(_)) => &self.drawables.black_resigned,
+            Some(GameOver::DrawAgreed) => &self.drawables.draw_agreed,
+            Some(GameOver::Timeout(Color::WHITE)) => &self.drawables.white_timeout,
+            Some(GameOver::Timeout(_)) => &self.drawables.black_timeout,
+            None => self.drawables.turn_banner(self.game.turn),
         };
 
-        canvas.draw(
-            text,
-            vec2((GRID_X * 2.0 + TILE_SIZE * 8.0) / 2.0, GRID_Y / 2.0),
-        );
+        canvas.draw(text, vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y / 2.0));
+
+        let below_board = vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y * 1.5 + TILE_SIZE * 8.0);
+        // Each player's name on their side of the board
+        let (top, bottom) = sides(self.flipped);
+        for (color, y) in [
+            (top, GRID_Y - 15.0),
+            (bottom, GRID_Y + TILE_SIZE * 8.0 + 12.0),
+        ] {
+            let label = match color {
+                Color::WHITE => "White",
+                _ => "Black",
+            };
+            let text = match self.opponent.local_color() {
+                Some(local_color) => {
+                    let name = match color == local_color {
+                        true => self.opponent.local_name().unwrap_or("You"),
+                        false => self.opponent.name().unwrap_or("Opponent"),
+                    };
+                    format!("{} ({})", name.chars().take(32).collect::<String>(), label)
+                }
+                // Both players sit at this window, no names to tell apart
+                None => label.to_string(),
+            };
+            let text = graphics::Text::new(text)
+                .set_layout(TextLayout {
+                    h_align: graphics::TextAlign::Begin,
+                    v_align: graphics::TextAlign::Middle,
+                })
+                .set_scale(20.0)
+                .clone();
+            canvas.draw(&text, vec2(GRID_X, y));
+        }
+
+        if let Some(clock) = &self.clock {
+            // Each clock beside its player's side of the board
+            for (color, y) in [
+                (top, GRID_Y + TILE_SIZE * 0.5),
+                (bottom, GRID_Y + TILE_SIZE * 7.5),
+            ] {
+                let text = graphics::Text::new(clock::format_time(clock.remaining(color)))
+                    .set_layout(TextLayout::center())
+                    .set_scale(28.0)
+                    .clone();
+                let text_color = match clock.running() == Some(color) {
+                    true => PINK_COLOR,
+                    false => WHITE_COLOR,
+                };
+                canvas.draw(
+                    &text,
+                    DrawParam::new()
+                        .dest(vec2(GRID_X * 1.5 + TILE_SIZE * 8.0, y))
+                        .color(text_color),
+                );
+            }
+        }
+
+        canvas.draw(&self.drawables.move_list_background, vec2(0.0, 0.0));
+        let rows = san::numbered(&self.history.start, &self.history.san);
+        let top = self.move_list_top(rows.len());
+        draw_move_list(&mut canvas, &rows, top, None);
+
+        if self.game_over.is_none() {
+            self.drawables.resign_button.draw(&mut canvas);
+            self.drawables.offer_draw_button.draw(&mut canvas);
+            self.drawables.takeback_button.draw(&mut canvas);
+        }
+
+        if let Some(message) = &self.message_text {
+            canvas.draw(message, below_board);
+        } else if let MoveState::AwaitingAck { .. } = self.move_state {
+            canvas.draw(&self.drawables.awaiting_ack, below_board);
+        } else if let MoveState::TakebackRequested { .. } = self.move_state {
+            canvas.draw(&self.drawables.takeback_pending, below_board);
+        } else if self.offer_draw {
+            canvas.draw(&self.drawables.draw_offer_pending, below_board);
+        }
+
+        let prompt = match self.move_state {
+            MoveState::DrawOffered => Some(&self.drawables.draw_offer_prompt),
+            MoveState::TakebackOffered { .. } => Some(&self.drawables.takeback_prompt),
+            _ => None,
+        };
+        if let Some(prompt) = prompt {
+            canvas.draw(&self.drawables.prompt_background, vec2(0.0, 0.0));
+            canvas.draw(
+                prompt,
+                vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y + TILE_SIZE * 3.5),
+            );
+            self.drawables.accept_button.draw(&mut canvas);
+            self.drawables.decline_button.draw(&mut canvas);
+        }
+
+        if self.show_fen {
+            draw_fen(&mut canvas, &self.drawables, &self.history.fen(&self.game));
+        }
 
         // Draw code here...
         canvas.finish(ctx)
@@ -412,49 +2013,96 @@ impl EventHandler for Chess {
 
     fn mouse_button_down_event(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         button: event::MouseButton,
         x: f32,
         y: f32,
     ) -> Result<(), ggez::GameError> {
-        if button != MouseButton::Left {
+        if button != MouseButton::Left || self.game_over.is_some() {
+            return Ok(());
+        }
+        let (x, y) = window2screen(ctx, x, y);
+        if let MoveState::DrawOffered | MoveState::TakebackOffered { .. } = self.move_state {
+            // Prompts are modal
+            if self.drawables.accept_button.contains(x, y) {
+                self.answer_prompt(true);
+            } else if self.drawables.decline_button.contains(x, y) {
+                self.answer_prompt(false);
+            }
+            return Ok(());
+        }
+        if self.drawables.resign_button.contains(x, y) {
+            self.resign();
+            return Ok(());
+        }
+        if self.drawables.offer_draw_button.contains(x, y) {
+            self.toggle_draw_offer();
+            return Ok(());
+        }
+        if self.drawables.takeback_button.contains(x, y) {
+            self.request_takeback();
+            return Ok(());
+        }
+        // Can only select or move piece when it is localplayer's turn and no move is in flight
+        if self
+            .opponent
+            .local_color()
+            .is_some_and(|color| color != self.game.turn)
+        {
             return Ok(());
         }
-        // Can only select or move piece when it is localplayer's turn
-        if self.game.turn != self.connection.local_color {
+        if let MoveState::AwaitingAck { .. } | MoveState::TakebackRequested { .. } = self.move_state
+        {
             return Ok(());
         }
-        let position = match self.grid.screen2grid(x, y) {
+        // Moves would go unanswered once the connection is lost
+        if self.disconnected {
+            return Ok(());
+        }
+        let position = match self.grid.screen2grid(x, y, self.flipped) {
             Some(t) => t,
             None => return Ok(()),
         };
 
+        if let Some(target) = self.promotion_target.take() {
+            // Promotion chooser open, pick a piece or cancel the move
+            if position.x as i32 == target.0 {
+                if let Some(&choice) = (0..4)
+                    .position(|i| promotion_row(target, i) == position.y)
+                    .and_then(|i| PROMOTION_CHOICES.get(i))
+                {
+                    self.requested_move = Some((target, Some(choice)));
+                    return Ok(());
+                }
+            }
+            self.selected_piece = None;
+            return Ok(());
+        }
+
         if let Some(selected) = &self.selected_piece {
             // Piece already selected, maybe move? :)
             if selected
                 .moves
                 .contains(&Move(position.x as i32, position.y as i32))
             {
-                /* move_piece(
-                    Move(position.x as i32, position.y as i32),
-                    selected.position.x as i32,
-                    selected.position.y as i32,
-                    &mut self.game,
-                )
-                .unwrap();
-                self.selected_piece = None; */
-                self.requested_move = Some(Move(position.x as i32, position.y as i32));
+                let target = Move(position.x as i32, position.y as i32);
+                let piece = self.game.board.pieces[selected.position.y][selected.position.x];
+                if is_promotion(piece.piece_type, target) {
+                    self.promotion_target = Some(target);
+                } else {
+                    self.requested_move = Some((target, None));
+                }
                 return Ok(());
             }
         }
 
         let piece = self.game.board.pieces[position.y][position.x];
         if piece.piece_type != PieceType::EMPTY && piece.color == self.game.turn {
-            let moves = get_legal_moves(
-                self.game.board,
+            let moves = rules::legal_moves(
+                &self.game,
+                &self.history,
                 position.x as i32,
                 position.y as i32,
-                piece.color,
             );
             self.selected_piece = Some(Selected { position, moves });
         } else {
@@ -463,6 +2111,56 @@ impl EventHandler for Chess {
         Ok(())
     }
 
+    fn key_down_event(
+        &mut self,
+        ctx: &mut Context,
+        input: KeyInput,
+        _repeated: bool,
+    ) -> Result<(), ggez::GameError> {
+        match input.keycode {
+            Some(KeyCode::Escape) => match self.move_state {
+                MoveState::TakebackRequested {
+                    cancelled: false, ..
+                } => self.cancel_takeback(),
+                _ => ctx.request_quit(),
+            },
+            Some(KeyCode::R) => self.resign(),
+            Some(KeyCode::D) => self.toggle_draw_offer(),
+            Some(KeyCode::U) => self.request_takeback(),
+            Some(KeyCode::Y) => self.answer_prompt(true),
+            Some(KeyCode::N) => self.answer_prompt(false),
+            Some(KeyCode::S) => self.save_pgn(true),
+            Some(KeyCode::F) => self.toggle_fen(),
+            Some(KeyCode::B) => self.flipped = !self.flipped,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> GameResult<bool> {
+        if self.game_over.is_none() {
+            self.save_game();
+        }
+        Ok(false)
+    }
+
+    fn mouse_wheel_event(&mut self, ctx: &mut Context, _x: f32, y: f32) -> GameResult {
+        let position = ctx.mouse.position();
+        if window2screen(ctx, position.x, position.y).0 < PANEL_X {
+            return Ok(());
+        }
+        // Scrolling to the end follows new moves again
+        let rows = san::numbered(&self.history.start, &self.history.san).len();
+        let last_page = rows.saturating_sub(MOVE_LIST_ROWS);
+        let top = self.move_list_top(rows);
+        let top = match y > 0.0 {
+            true => top.saturating_sub(1),
+            false => top + 1,
+        };
+        self.move_list_scroll = (top < last_page).then_some(top);
+        Ok(())
+    }
+
     fn mouse_button_up_event(
         &mut self,
         _ctx: &mut Context,
@@ -1,9 +1,9 @@
 use chess_lib::board::pieces::Color;
-use ggez::{GameError, GameResult};
+use ggez::GameError;
 use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use std::{
-    io::{BufWriter, Write},
+    io::{BufWriter, ErrorKind, Read, Write},
     net::{TcpListener, TcpStream},
     sync::Arc,
     thread,
@@ -19,13 +19,64 @@ pub enum MultiplayerStatus {
 pub struct Connection {
     pub multiplayer_status: MultiplayerStatus,
     pub local_color: Color,
+    pub local_name: String,
+    /// Name the opponent sent in their Start packet
+    pub opponent_name: Option<String>,
+    /// Whether the opponent announced that it understands takeback packets
+    pub takebacks: bool,
     stream: BufWriter<TcpStream>,
+    // Received bytes that have not been decoded into a packet yet
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+/// Every packet kind of the chess-networking protocol, plus our takeback extension
+#[derive(Debug)]
+pub enum Message {
+    Start(chess_networking::Start),
+    Move(chess_networking::Move),
+    Ack(chess_networking::Ack),
+    Takeback(Takeback),
+}
+
+/// Takeback negotiation. This is not part of the chess-networking protocol, so peers
+/// running other clients skip these packets as unknown and never answer a request,
+/// so requests are only sent to peers that announced support during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Takeback {
+    /// Sent by both sides right after the Start packets to announce support
+    Supported,
+    /// Asks to take back the last `moves` halfmoves, after which it is the asking
+    /// player's turn
+    Request {
+        moves: u8,
+    },
+    Accept,
+    Decline,
+    /// Withdraws a request. The request is still answered, declined unless it was
+    /// accepted before the withdrawal arrived.
+    Cancel,
+}
+
+/// Moves a resumed game has reached from its starting position, exchanged after the
+/// Start packets. Like takebacks this is not part of the chess-networking protocol,
+/// but only this client can resume games.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeMoves {
+    /// Each move as e.g. "e2e4", or "e7e8q" with a promotion piece
+    pub moves: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum ReadError {
     IO(std::io::Error),
     Decode(rmp_serde::decode::Error),
+    /// No complete packet has arrived yet
+    Incomplete,
+    /// The peer closed the connection
+    Disconnected,
+    /// A packet that is not part of the protocol was received and skipped
+    Unknown,
 }
 impl From<std::io::Error> for ReadError {
     fn from(err: std::io::Error) -> ReadError {
@@ -59,6 +110,9 @@ impl From<ReadError> for GameError {
         match err {
             ReadError::IO(e) => GameError::IOError(Arc::new(e)),
             ReadError::Decode(e) => GameError::CustomError(e.to_string()),
+            ReadError::Incomplete => GameError::CustomError("Incomplete packet".to_string()),
+            ReadError::Disconnected => GameError::CustomError("Opponent disconnected".to_string()),
+            ReadError::Unknown => GameError::CustomError("Unknown packet".to_string()),
         }
     }
 }
@@ -73,8 +127,8 @@ impl From<WriteError> for GameError {
 }
 
 impl Connection {
-    pub fn server(port: u16) -> std::io::Result<Connection> {
-        let listener = TcpListener::bind(("0.0.0.0", port))?;
+    pub fn server(addr: &str, port: u16) -> std::io::Result<Connection> {
+        let listener = TcpListener::bind((addr, port))?;
         let (stream, _addr) = listener.accept()?;
         stream.set_nonblocking(true)?;
 
@@ -82,6 +136,11 @@ impl Connection {
             multiplayer_status: MultiplayerStatus::Server,
             stream: BufWriter::new(stream),
             local_color: Color::EMPTY,
+            local_name: String::new(),
+            opponent_name: None,
+            takebacks: false,
+            buffer: Vec::new(),
+            closed: false,
         })
     }
 
@@ -93,6 +152,11 @@ impl Connection {
             multiplayer_status: MultiplayerStatus::Client,
             stream: BufWriter::new(stream),
             local_color: Color::EMPTY,
+            local_name: String::new(),
+            opponent_name: None,
+            takebacks: false,
+            buffer: Vec::new(),
+            closed: false,
         })
     }
 
@@ -103,22 +167,223 @@ impl Connection {
         Ok(())
     }
 
+    /// Moves everything currently available on the socket into the receive buffer
+    fn fill_buffer(&mut self) -> Result<(), ReadError> {
+        let mut chunk = [0; 4096];
+        while !self.closed {
+            match self.stream.get_mut().read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the packet at the front of the receive buffer without consuming it,
+    /// returning it along with its length in bytes
+    fn decode<T: for<'a> Deserialize<'a>>(&self) -> Result<(T, usize), ReadError> {
+        let mut remaining = &self.buffer[..];
+        let result = T::deserialize(&mut Deserializer::new(&mut remaining));
+        match result {
+            Ok(packet) => Ok((packet, self.buffer.len() - remaining.len())),
+            Err(
+                rmp_serde::decode::Error::InvalidMarkerRead(e)
+                | rmp_serde::decode::Error::InvalidDataRead(e),
+            ) if e.kind() == ErrorKind::UnexpectedEof => match self.closed {
+                true => Err(ReadError::Disconnected),
+                false => Err(ReadError::Incomplete),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the next packet if it has fully arrived. Partial packets, and packets that
+    /// do not decode as `T`, are left in the buffer for later calls.
     pub fn read<T: for<'a> Deserialize<'a> + std::fmt::Debug>(&mut self) -> Result<T, ReadError> {
-        let mut de = Deserializer::new(self.stream.get_mut());
-        let packet = T::deserialize(&mut de)?;
+        self.fill_buffer()?;
+        let (packet, len) = self.decode::<T>()?;
+        self.buffer.drain(..len);
         println!("Receiving: {:?}", packet);
         Ok(packet)
     }
 
+    /// Reads the next packet of any kind if it has fully arrived.
+    ///
+    /// The packet kinds are tried in a fixed order (Move, Start, Ack, Takeback). Their
+    /// field layouts differ in length or in the type of the first field, and Takeback
+    /// is encoded as a variant name rather than a list of fields, so at most one of
+    /// them can decode a given packet. Packets matching none of them are skipped, and
+    /// bytes that are not MessagePack are dropped along with everything after them.
+    pub fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        self.fill_buffer()?;
+
+        let attempts = [
+            self.decode().map(|(p, len)| (Message::Move(p), len)),
+            self.decode().map(|(p, len)| (Message::Start(p), len)),
+            self.decode().map(|(p, len)| (Message::Ack(p), len)),
+            self.decode().map(|(p, len)| (Message::Takeback(p), len)),
+        ];
+        let mut incomplete = false;
+        for attempt in attempts {
+            match attempt {
+                Ok((message, len)) => {
+                    self.buffer.drain(..len);
+                    if let Message::Takeback(_) = message {
+                        // Only clients that understand takebacks send these
+                        self.takebacks = true;
+                    }
+                    println!("Receiving: {:?}", message);
+                    return Ok(Some(message));
+                }
+                Err(ReadError::Incomplete) | Err(ReadError::Disconnected) => incomplete = true,
+                Err(_) => {}
+            }
+        }
+        if incomplete {
+            return match self.closed {
+                true => Err(ReadError::Disconnected),
+                false => Ok(None),
+            };
+        }
+
+        // Not a protocol packet, skip over it so later packets can still be read
+        match self.decode::<IgnoredAny>() {
+            Ok((_, len)) => {
+                self.buffer.drain(..len);
+                Err(ReadError::Unknown)
+            }
+            Err(ReadError::Incomplete) => Ok(None),
+            Err(ReadError::Decode(e)) => {
+                // Not MessagePack at all, so there is no telling where the next packet starts
+                self.buffer.clear();
+                Err(ReadError::Decode(e))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn read_block<T: for<'a> Deserialize<'a> + std::fmt::Debug>(
         &mut self,
     ) -> Result<T, ReadError> {
         loop {
-            let packet = self.read::<T>();
-            if let Ok(p) = packet {
-                return Ok(p);
-            };
-            thread::sleep(Duration::from_millis(20));
+            match self.read::<T>() {
+                Err(ReadError::Incomplete) => thread::sleep(Duration::from_millis(20)),
+                packet => return packet,
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection and the raw stream of its peer
+    fn connected() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let connection = Connection {
+            multiplayer_status: MultiplayerStatus::Server,
+            local_color: Color::EMPTY,
+            local_name: String::new(),
+            opponent_name: None,
+            takebacks: false,
+            stream: BufWriter::new(stream),
+            buffer: Vec::new(),
+            closed: false,
+        };
+        (connection, peer)
+    }
+
+    fn move_packet() -> chess_networking::Move {
+        chess_networking::Move {
+            from: (4, 1),
+            to: (4, 3),
+            promotion: None,
+            forfeit: false,
+            offer_draw: false,
+        }
+    }
+
+    /// Polls until a packet has arrived, as the peer's bytes take a moment to show up
+    fn poll_until_ready(connection: &mut Connection) -> Message {
+        for _ in 0..100 {
+            if let Some(message) = connection.poll().unwrap() {
+                return message;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no packet arrived");
+    }
+
+    #[test]
+    fn packet_split_across_reads() {
+        let (mut connection, mut peer) = connected();
+        let bytes = rmp_serde::to_vec(&move_packet()).unwrap();
+        let (first, rest) = bytes.split_at(bytes.len() / 2);
+
+        peer.write_all(first).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(connection.poll().unwrap().is_none());
+
+        peer.write_all(rest).unwrap();
+        match poll_until_ready(&mut connection) {
+            Message::Move(mov) => assert_eq!((mov.from, mov.to), ((4, 1), (4, 3))),
+            message => panic!("expected a move, got {:?}", message),
+        }
+        assert!(connection.buffer.is_empty());
+    }
+
+    #[test]
+    fn packets_merged_into_one_read() {
+        let (mut connection, mut peer) = connected();
+        let mut bytes = rmp_serde::to_vec(&move_packet()).unwrap();
+        let ack = chess_networking::Ack {
+            ok: true,
+            end_state: None,
+        };
+        bytes.extend(rmp_serde::to_vec(&ack).unwrap());
+        bytes.extend(rmp_serde::to_vec(&Takeback::Request { moves: 2 }).unwrap());
+        peer.write_all(&bytes).unwrap();
+
+        assert!(matches!(
+            poll_until_ready(&mut connection),
+            Message::Move(_)
+        ));
+        assert!(matches!(
+            connection.poll().unwrap(),
+            Some(Message::Ack(chess_networking::Ack { ok: true, .. }))
+        ));
+        assert!(matches!(
+            connection.poll().unwrap(),
+            Some(Message::Takeback(Takeback::Request { moves: 2 }))
+        ));
+        assert!(connection.takebacks);
+        assert!(connection.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_packet_is_skipped() {
+        let (mut connection, mut peer) = connected();
+        let mut bytes = rmp_serde::to_vec(&("not", "a", "packet", 1, 2, 3)).unwrap();
+        bytes.extend(rmp_serde::to_vec(&move_packet()).unwrap());
+        peer.write_all(&bytes).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(matches!(connection.poll(), Err(ReadError::Unknown)));
+        assert!(matches!(connection.poll().unwrap(), Some(Message::Move(_))));
+    }
+
+    #[test]
+    fn closed_connection_is_reported() {
+        let (mut connection, peer) = connected();
+        drop(peer);
+        thread::sleep(Duration::from_millis(50));
+        assert!(matches!(connection.poll(), Err(ReadError::Disconnected)));
+    }
+}
@@ -0,0 +1,342 @@
+use chess_lib::board::pieces::{Color, Move};
+use chess_lib::game::Game;
+use chess_networking::{Ack, PromotionPiece};
+use ggez::{GameError, GameResult};
+use std::collections::VecDeque;
+use std::{fs, path::Path};
+
+use crate::fen::{self, Fen};
+use crate::grid::GridPosition;
+use crate::networking::{Connection, Message, ReadError, Takeback};
+use crate::rules::{self, PlayedMove, PositionHistory};
+
+/// Whoever plays against the local player. Everything goes through the packets of the
+/// chess-networking protocol, so `Chess` runs the same move handshake no matter who
+/// sits on the other side.
+pub trait Opponent {
+    /// Color played from this window, None when both colors are
+    fn local_color(&self) -> Option<Color>;
+
+    /// Name of the local player, if the opponent knows it
+    fn local_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Name shown for the opponent
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Sends our move, or our forfeit, to the opponent
+    fn send_move(&mut self, mov: chess_networking::Move) -> GameResult;
+
+    /// Answers a move the opponent made
+    fn send_ack(&mut self, ack: Ack) -> GameResult;
+
+    /// Asks for or answers a takeback
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult;
+
+    /// Whether the opponent understands takeback packets
+    fn supports_takebacks(&self) -> bool {
+        true
+    }
+
+    /// Next packet from the opponent, if one has arrived
+    fn poll(&mut self) -> Result<Option<Message>, ReadError>;
+}
+
+/// Opponent on the other end of a TCP connection
+impl Opponent for Connection {
+    fn local_color(&self) -> Option<Color> {
+        Some(self.local_color)
+    }
+
+    fn local_name(&self) -> Option<&str> {
+        Some(&self.local_name)
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.opponent_name.as_deref()
+    }
+
+    fn send_move(&mut self, mov: chess_networking::Move) -> GameResult {
+        Ok(self.write(mov)?)
+    }
+
+    fn send_ack(&mut self, ack: Ack) -> GameResult {
+        Ok(self.write(ack)?)
+    }
+
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult {
+        Ok(self.write(takeback)?)
+    }
+
+    fn supports_takebacks(&self) -> bool {
+        self.takebacks
+    }
+
+    fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        Connection::poll(self)
+    }
+}
+
+/// Second player at the same window. Both colors are moved through the board, so
+/// there is nothing to send or receive, except that takebacks are granted right away.
+#[derive(Default)]
+pub struct LocalHuman {
+    takeback_granted: bool,
+}
+
+impl Opponent for LocalHuman {
+    fn local_color(&self) -> Option<Color> {
+        None
+    }
+
+    fn send_move(&mut self, _mov: chess_networking::Move) -> GameResult {
+        Ok(())
+    }
+
+    fn send_ack(&mut self, _ack: Ack) -> GameResult {
+        Ok(())
+    }
+
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult {
+        self.takeback_granted = matches!(takeback, Takeback::Request { .. });
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        Ok(std::mem::take(&mut self.takeback_granted)
+            .then_some(Message::Takeback(Takeback::Accept)))
+    }
+}
+
+/// Opponent playing a fixed list of moves, one after each of ours, and resigning once
+/// it runs out. Every move of ours is acked without looking at the board, so it does
+/// not report game endings either.
+pub struct ScriptedOpponent {
+    color: Color,
+    moves: VecDeque<chess_networking::Move>,
+    // Packets waiting to be picked up by poll
+    outbox: VecDeque<Message>,
+}
+
+impl ScriptedOpponent {
+    /// Plays `moves` as `color`, starting right away if `moves_first`
+    pub fn new(
+        color: Color,
+        moves: Vec<chess_networking::Move>,
+        moves_first: bool,
+    ) -> ScriptedOpponent {
+        let mut opponent = ScriptedOpponent {
+            color,
+            moves: moves.into(),
+            outbox: VecDeque::new(),
+        };
+        if moves_first {
+            opponent.play_next();
+        }
+        opponent
+    }
+
+    /// Reads a script of moves in coordinate notation such as "e2e4" or "e7e8q",
+    /// separated by whitespace
+    pub fn from_file(path: &Path, color: Color, moves_first: bool) -> GameResult<ScriptedOpponent> {
+        let script = fs::read_to_string(path)?;
+        let moves = script
+            .split_whitespace()
+            .map(|mov| {
+                parse_coordinate_move(mov).ok_or_else(|| {
+                    GameError::CustomError(format!("Invalid move '{}' in {}", mov, path.display()))
+                })
+            })
+            .collect::<GameResult<_>>()?;
+        Ok(ScriptedOpponent::new(color, moves, moves_first))
+    }
+
+    fn play_next(&mut self) {
+        let mov = self.moves.pop_front().unwrap_or_else(forfeit);
+        self.outbox.push_back(Message::Move(mov));
+    }
+}
+
+impl Opponent for ScriptedOpponent {
+    fn local_color(&self) -> Option<Color> {
+        Some(rules::opponent(self.color))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("Script")
+    }
+
+    fn send_move(&mut self, mov: chess_networking::Move) -> GameResult {
+        self.outbox.push_back(Message::Ack(Ack {
+            ok: true,
+            end_state: None,
+        }));
+        if !mov.forfeit {
+            self.play_next();
+        }
+        Ok(())
+    }
+
+    fn send_ack(&mut self, ack: Ack) -> GameResult {
+        if !ack.ok {
+            // Everything after a rejected move would be out of sync
+            self.moves.clear();
+            self.play_next();
+        }
+        Ok(())
+    }
+
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult {
+        // A script only goes forwards
+        if let Takeback::Request { .. } = takeback {
+            self.outbox.push_back(Message::Takeback(Takeback::Decline));
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        Ok(self.outbox.pop_front())
+    }
+}
+
+/// Copy of the game kept by opponents that live in this process, so they can check
+/// our moves and report game endings in their acks like a peer would
+pub struct GameCopy {
+    pub game: Game,
+    pub history: PositionHistory,
+}
+
+impl GameCopy {
+    pub fn new(start: &Fen) -> GameCopy {
+        let game = start.to_game();
+        let history = PositionHistory::new(&game, start);
+        GameCopy { game, history }
+    }
+
+    /// Takes back the last `moves` halfmoves, returning false if there are not as
+    /// many
+    pub fn take_back(&mut self, moves: usize) -> bool {
+        rules::take_back(&mut self.game, &mut self.history, moves)
+    }
+
+    /// Checks a move of the local player and plays it if it is legal, giving the ack
+    /// to answer it with
+    pub fn ack(&mut self, mov: &chess_networking::Move) -> Ack {
+        let ok = self.apply(mov);
+        let ending = match ok {
+            true => rules::ending(&self.game, &self.history),
+            false => None,
+        };
+        Ack {
+            ok,
+            end_state: ending.map(crate::end_state),
+        }
+    }
+
+    fn apply(&mut self, mov: &chess_networking::Move) -> bool {
+        if mov.from.0 > 7 || mov.from.1 > 7 || mov.to.0 > 7 || mov.to.1 > 7 {
+            return false;
+        }
+        let from: GridPosition = (mov.from.0 as usize, 7 - mov.from.1 as usize).into(); // Adjust y
+        let to = Move(mov.to.0 as i32, 7 - mov.to.1 as i32); // Adjust y
+        let piece = self.game.board.pieces[from.y][from.x];
+        if piece.color != self.game.turn {
+            return false;
+        }
+        let legal = rules::legal_moves(&self.game, &self.history, from.x as i32, from.y as i32);
+        let promotion = match crate::is_promotion(piece.piece_type, to) {
+            true => Some(mov.promotion.unwrap_or(PromotionPiece::Queen)),
+            false => None,
+        };
+        legal.contains(&to)
+            && rules::play_move(&mut self.game, &mut self.history, from, to, promotion)
+    }
+
+    /// Plays a move chosen by the opponent, promoting to a queen unless told
+    /// otherwise, and gives the packet announcing it. None if chess_lib refuses it.
+    pub fn play(
+        &mut self,
+        from: GridPosition,
+        to: Move,
+        promotion: Option<PromotionPiece>,
+    ) -> Option<chess_networking::Move> {
+        let piece = self.game.board.pieces[from.y][from.x];
+        let promotion = match crate::is_promotion(piece.piece_type, to) {
+            true => Some(promotion.unwrap_or(PromotionPiece::Queen)),
+            false => None,
+        };
+        if !rules::play_move(&mut self.game, &mut self.history, from, to, promotion) {
+            return None;
+        }
+        Some(move_packet(PlayedMove {
+            from,
+            to,
+            promotion,
+        }))
+    }
+}
+
+/// Packet announcing a move that was played
+pub fn move_packet(played: PlayedMove) -> chess_networking::Move {
+    chess_networking::Move {
+        from: (played.from.x as u8, 7 - played.from.y as u8), // Adjust y
+        to: (played.to.0 as u8, 7 - played.to.1 as u8),       // Adjust y
+        promotion: played.promotion,
+        forfeit: false,
+        offer_draw: false,
+    }
+}
+
+/// The move packet used to resign
+pub fn forfeit() -> chess_networking::Move {
+    chess_networking::Move {
+        from: (0, 0),
+        to: (0, 0),
+        promotion: None,
+        forfeit: true,
+        offer_draw: false,
+    }
+}
+
+/// Parses a move such as "e2e4", or "e7e8q" with a promotion piece, into protocol
+/// coordinates
+pub fn parse_coordinate_move(mov: &str) -> Option<chess_networking::Move> {
+    let from = fen::parse_square(mov.get(0..2)?)?;
+    let to = fen::parse_square(mov.get(2..4)?)?;
+    let promotion = match mov.get(4..)? {
+        "" => None,
+        "q" => Some(PromotionPiece::Queen),
+        "r" => Some(PromotionPiece::Rook),
+        "b" => Some(PromotionPiece::Bishop),
+        "n" => Some(PromotionPiece::Knight),
+        _ => return None,
+    };
+    Some(chess_networking::Move {
+        // Protocol rows count from white's side
+        from: (from.0 as u8, 7 - from.1 as u8),
+        to: (to.0 as u8, 7 - to.1 as u8),
+        promotion,
+        forfeit: false,
+        offer_draw: false,
+    })
+}
+
+/// Writes a move in protocol coordinates as e.g. "e2e4" or "e7e8q"
+pub fn coordinate_move(mov: &chess_networking::Move) -> String {
+    let promotion = match mov.promotion {
+        None => "",
+        Some(PromotionPiece::Queen) => "q",
+        Some(PromotionPiece::Rook) => "r",
+        Some(PromotionPiece::Bishop) => "b",
+        Some(PromotionPiece::Knight) => "n",
+    };
+    format!(
+        "{}{}{}",
+        fen::square_name(Move(mov.from.0 as i32, 7 - mov.from.1 as i32)),
+        fen::square_name(Move(mov.to.0 as i32, 7 - mov.to.1 as i32)),
+        promotion
+    )
+}
@@ -0,0 +1,324 @@
+use ggez::GameError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fen::{self, Fen, FenError};
+use crate::san;
+
+// Movetext lines stay below the 80 columns the PGN standard allows
+const LINE_LENGTH: usize = 79;
+
+/// A game in Portable Game Notation
+pub struct Pgn {
+    /// Tag pairs in the order they are written, the Seven Tag Roster first
+    pub tags: Vec<(String, String)>,
+    /// Moves in standard algebraic notation
+    pub moves: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum PgnError {
+    Tag,
+    Comment,
+    Variation,
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self {
+            PgnError::Tag => "malformed tag pair",
+            PgnError::Comment => "unterminated comment",
+            PgnError::Variation => "unbalanced variation",
+        };
+        write!(f, "Invalid PGN: {}", what)
+    }
+}
+
+impl From<PgnError> for GameError {
+    fn from(err: PgnError) -> GameError {
+        GameError::CustomError(err.to_string())
+    }
+}
+
+impl Pgn {
+    /// Parses the first game in `text`. Comments, numeric annotation glyphs and
+    /// variations are skipped, and the moves are not checked against the board.
+    pub fn parse(text: &str) -> Result<Pgn, PgnError> {
+        let mut tags = Vec::new();
+        let mut moves = Vec::new();
+        // Nesting depth of the variation being skipped
+        let mut depth = 0;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                // Tags after the movetext belong to the next game
+                '[' if !moves.is_empty() => break,
+                '[' => tags.push(parse_tag(&mut chars)?),
+                '{' => {
+                    if !chars.by_ref().any(|c| c == '}') {
+                        return Err(PgnError::Comment);
+                    }
+                }
+                ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+                '(' => depth += 1,
+                ')' if depth == 0 => return Err(PgnError::Variation),
+                ')' => depth -= 1,
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(c) =
+                        chars.next_if(|&c| !c.is_whitespace() && !"[]{}();".contains(c))
+                    {
+                        token.push(c);
+                    }
+                    // Annotation glyphs are either numeric or written apart like "!?"
+                    let annotation =
+                        token.starts_with('$') || token.chars().all(|c| c == '!' || c == '?');
+                    if depth > 0 || annotation {
+                        continue;
+                    }
+                    if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token.as_str()) {
+                        break;
+                    }
+                    // Move numbers such as "12." or "12...", which may run into the move
+                    let rest = token.trim_start_matches(|c: char| c.is_ascii_digit());
+                    let san = match rest.starts_with('.') {
+                        true => rest.trim_start_matches('.'),
+                        false => &token,
+                    };
+                    if !san.is_empty() {
+                        moves.push(san.to_string());
+                    }
+                }
+            }
+        }
+        if depth > 0 {
+            return Err(PgnError::Variation);
+        }
+        Ok(Pgn { tags, moves })
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Position the moves start from, given by the FEN tag for games that do not
+    /// start from the standard position
+    pub fn start(&self) -> Result<Fen, FenError> {
+        Fen::parse(self.tag("FEN").unwrap_or(fen::STARTING_FEN))
+    }
+
+    /// Writes the game to `path`, creating its directory if needed
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Pgn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.tags {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(f, "[{} \"{}\"]", name, value)?;
+        }
+        writeln!(f)?;
+
+        // A broken FEN tag only throws off the move numbers
+        let start = self
+            .start()
+            .unwrap_or_else(|_| Fen::parse(fen::STARTING_FEN).unwrap());
+        let rows = san::numbered(&start, &self.moves);
+        let result = self.tag("Result").unwrap_or("*");
+        let mut line = String::new();
+        for token in rows.iter().flat_map(|row| row.split(' ')).chain([result]) {
+            if !line.is_empty() && line.len() + 1 + token.len() > LINE_LENGTH {
+                writeln!(f, "{}", line)?;
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(token);
+        }
+        writeln!(f, "{}", line)
+    }
+}
+
+/// Reads a tag pair such as `[White "Fischer, Robert J."]` after its opening
+/// bracket
+fn parse_tag(chars: &mut Peekable<Chars>) -> Result<(String, String), PgnError> {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '"') {
+        name.push(c);
+    }
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    if name.is_empty() || chars.next() != Some('"') {
+        return Err(PgnError::Tag);
+    }
+    let mut value = String::new();
+    loop {
+        match chars.next().ok_or(PgnError::Tag)? {
+            '"' => break,
+            '\\' => value.push(chars.next().ok_or(PgnError::Tag)?),
+            c => value.push(c),
+        }
+    }
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    match chars.next() {
+        Some(']') => Ok((name, value)),
+        _ => Err(PgnError::Tag),
+    }
+}
+
+/// Value of the Date tag for a game started at `time`, e.g. "2024.03.09"
+pub fn date(time: SystemTime) -> String {
+    let (year, month, day, _) = utc(time);
+    format!("{}.{:02}.{:02}", year, month, day)
+}
+
+/// Name of the file a game started at `time` is saved to, e.g.
+/// "2024-03-09_181502_alice_vs_bob.pgn"
+pub fn file_name(time: SystemTime, white: &str, black: &str) -> String {
+    let (year, month, day, seconds) = utc(time);
+    let clean = |name: &str| -> String {
+        name.chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect()
+    };
+    format!(
+        "{}-{:02}-{:02}_{:02}{:02}{:02}_{}_vs_{}.pgn",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        clean(white),
+        clean(black)
+    )
+}
+
+/// Year, month, day and seconds into the day in UTC
+fn utc(time: SystemTime) -> (u64, u64, u64, u64) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    // Days since 1 March of year 0 in the proleptic Gregorian calendar, whose 400
+    // year eras all have the same length
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so the leap day comes last
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = match month < 10 {
+        true => (era * 400 + year_of_era, month + 3),
+        false => (era * 400 + year_of_era + 1, month - 9),
+    };
+    (year, month, day, seconds % 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn parses_tags_and_moves() {
+        let pgn = Pgn::parse(
+            "[Event \"Casual \\\"blitz\\\"\"]\n[White \"Fischer, Robert J.\"]\n\n\
+             1. e4 e5 2.Nf3 {Develops} Nc6 $1 3. Bb5 !? (3. Bc4 Bc5 (3... Nf6)) 3...a6 \
+             ; rest of the line\n4. Ba4 1-0\n\n[Event \"Next game\"]\n1. d4 *",
+        )
+        .unwrap();
+        assert_eq!(pgn.tag("Event"), Some("Casual \"blitz\""));
+        assert_eq!(pgn.tag("White"), Some("Fischer, Robert J."));
+        assert_eq!(pgn.tag("Black"), None);
+        assert_eq!(pgn.moves, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Ba4"]);
+    }
+
+    #[test]
+    fn rejects_malformed_games() {
+        let errors = [
+            ("[Event \"Open", "malformed tag pair"),
+            ("[Event Open]", "malformed tag pair"),
+            ("1. e4 {unterminated", "unterminated comment"),
+            ("1. e4 (1. d4", "unbalanced variation"),
+            ("1. e4 ) e5", "unbalanced variation"),
+        ];
+        for (text, what) in errors {
+            let err = Pgn::parse(text).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid PGN: {}", what),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn writes_numbered_movetext() {
+        let pgn = Pgn {
+            tags: vec![
+                ("White".to_string(), "A \"quoted\" name".to_string()),
+                ("Result".to_string(), "*".to_string()),
+                (
+                    "FEN".to_string(),
+                    "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1".to_string(),
+                ),
+            ],
+            moves: vec!["e5".to_string(), "Nf3".to_string(), "Nc6".to_string()],
+        };
+        let text = pgn.to_string();
+        assert_eq!(
+            text,
+            "[White \"A \\\"quoted\\\" name\"]\n[Result \"*\"]\n\
+             [FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"]\n\n\
+             1... e5 2. Nf3 Nc6 *\n"
+        );
+        let parsed = Pgn::parse(&text).unwrap();
+        assert_eq!(parsed.tags, pgn.tags);
+        assert_eq!(parsed.moves, pgn.moves);
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(utc(at(0)), (1970, 1, 1, 0));
+        // Leap day, and the last second of a day in a century year that is no leap year
+        assert_eq!(utc(at(951782400)), (2000, 2, 29, 0));
+        assert_eq!(utc(at(4107542399)), (2100, 2, 28, 86399));
+        assert_eq!(utc(at(1709980502)), (2024, 3, 9, 38102));
+        // Before the epoch counts as the epoch
+        assert_eq!(utc(UNIX_EPOCH - Duration::from_secs(1)), (1970, 1, 1, 0));
+    }
+
+    #[test]
+    fn dates_and_file_names() {
+        assert_eq!(date(at(1709980502)), "2024.03.09");
+        assert_eq!(
+            file_name(at(1709980502), "alice", "Bob Smith/2"),
+            "2024-03-09_103502_alice_vs_Bob_Smith_2.pgn"
+        );
+    }
+}
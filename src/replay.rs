@@ -0,0 +1,258 @@
+use chess_lib::board::pieces::{Color, PieceType};
+use chess_lib::game::Game;
+use ggez::event::EventHandler;
+use ggez::glam::vec2;
+use ggez::graphics::{self, Rect, TextLayout};
+use ggez::input::keyboard::{KeyCode, KeyInput};
+use ggez::{Context, GameError, GameResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::fen;
+use crate::grid::Grid;
+use crate::pgn::Pgn;
+use crate::rules::{self, PlayedMove, PositionHistory};
+use crate::{board2grid, draw_coordinates, draw_fen, draw_move_list, draw_piece, san, sides};
+use crate::{Drawables, PieceImages};
+use crate::{GRID_X, GRID_Y, MOVE_LIST_ROWS, TILE_SIZE, WINDOW_HEIGHT, WINDOW_WIDTH};
+
+/// Reads the first game of a PGN file and plays its moves on the board, giving the
+/// final position. The moves of the returned game are rewritten in the notation the
+/// move list uses.
+pub fn load(path: &Path) -> GameResult<(Pgn, Game, PositionHistory)> {
+    let mut pgn = Pgn::parse(&fs::read_to_string(path)?)?;
+    let start = pgn.start()?;
+    let mut game = start.to_game();
+    let mut history = PositionHistory::new(&game, &start);
+    for (i, written) in pgn.moves.iter().enumerate() {
+        let legal = match san::parse(&game, &history, written) {
+            Some(played) => rules::play_move(
+                &mut game,
+                &mut history,
+                played.from,
+                played.to,
+                played.promotion,
+            ),
+            None => false,
+        };
+        if !legal {
+            return Err(GameError::CustomError(format!(
+                "Illegal move {} (halfmove {}) in {}",
+                written,
+                i + 1,
+                path.display()
+            )));
+        }
+    }
+    pgn.moves = history.san.clone();
+    Ok((pgn, game, history))
+}
+
+/// Viewer for a finished game. The arrow keys step through it one halfmove at a
+/// time, Home and End jump to either end.
+pub struct Replay {
+    pgn: Pgn,
+    moves: Vec<PlayedMove>,
+    game: Game,
+    history: PositionHistory,
+    grid: Grid,
+    piece_images: PieceImages,
+    drawables: Drawables,
+    // Move list rows of the whole game
+    rows: Vec<String>,
+    // Where F writes the position, stdout when None
+    fen_file: Option<PathBuf>,
+    show_fen: bool,
+    // Show the board with rank 1 at the top
+    flipped: bool,
+}
+
+impl Replay {
+    pub fn new(
+        ctx: &mut Context,
+        pgn: Pgn,
+        moves: Vec<PlayedMove>,
+        fen_file: Option<PathBuf>,
+    ) -> GameResult<Replay> {
+        let start = pgn.start()?;
+        let game = start.to_game();
+        Ok(Replay {
+            history: PositionHistory::new(&game, &start),
+            rows: san::numbered(&start, &pgn.moves),
+            game,
+            pgn,
+            moves,
+            grid: Grid::new(ctx)?,
+            piece_images: PieceImages::new(ctx)?,
+            drawables: Drawables::new(ctx)?,
+            fen_file,
+            show_fen: false,
+            flipped: false,
+        })
+    }
+
+    fn forward(&mut self) -> bool {
+        let Some(&played) = self.moves.get(self.history.moves.len()) else {
+            return false;
+        };
+        rules::play_move(
+            &mut self.game,
+            &mut self.history,
+            played.from,
+            played.to,
+            played.promotion,
+        )
+    }
+
+    fn back(&mut self, moves: usize) {
+        let moves = moves.min(self.history.moves.len());
+        rules::take_back(&mut self.game, &mut self.history, moves);
+    }
+
+    /// Shows or hides the FEN of the position on the board, writing it out when shown
+    fn toggle_fen(&mut self) {
+        self.show_fen = !self.show_fen;
+        if self.show_fen {
+            let fen = self.history.fen(&self.game);
+            if let Err(e) = fen::export(&fen, self.fen_file.as_deref()) {
+                println!("Error: Could not write FEN: {}", e);
+            }
+        }
+    }
+
+    /// Move list row holding the last move played, None before the first move
+    fn current_row(&self) -> Option<usize> {
+        let black_first = self.history.start.turn == Color::BLACK;
+        let played = self.history.moves.len();
+        (played > 0).then(|| (played - 1 + black_first as usize) / 2)
+    }
+}
+
+impl EventHandler for Replay {
+    fn update(&mut self, _ctx: &mut Context) -> GameResult {
+        Ok(())
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        let mut canvas = graphics::Canvas::from_frame(ctx, graphics::Color::BLACK);
+        canvas.set_screen_coordinates(Rect::new(0.0, 0.0, WINDOW_WIDTH, WINDOW_HEIGHT));
+        canvas.set_sampler(graphics::Sampler::linear_clamp());
+        canvas.draw(&self.grid.mesh, vec2(GRID_X, GRID_Y));
+        draw_coordinates(&mut canvas, self.flipped);
+
+        // Frame both squares of the last move
+        if let Some(last) = self.history.moves.last() {
+            canvas.draw(
+                &self.drawables.selected_frame,
+                board2grid(last.from.x, last.from.y, self.flipped),
+            );
+            canvas.draw(
+                &self.drawables.selected_frame,
+                board2grid(last.to.0 as usize, last.to.1 as usize, self.flipped),
+            );
+        }
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let piece = &self.game.board.pieces[y][x];
+                if piece.piece_type == PieceType::EMPTY {
+                    continue;
+                }
+                draw_piece(
+                    &self.piece_images,
+                    &mut canvas,
+                    board2grid(x, y, self.flipped),
+                    piece.piece_type,
+                    piece.color,
+                    1.0,
+                );
+            }
+        }
+
+        let banner = match rules::ending(&self.game, &self.history) {
+            Some(ending) => self.drawables.ending_banner(ending),
+            None => self.drawables.turn_banner(self.game.turn),
+        };
+        canvas.draw(banner, vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y / 2.0));
+
+        // Each player's name on their side of the board
+        let (top, bottom) = sides(self.flipped);
+        for (color, y) in [
+            (top, GRID_Y - 15.0),
+            (bottom, GRID_Y + TILE_SIZE * 8.0 + 12.0),
+        ] {
+            let label = match color {
+                Color::WHITE => "White",
+                _ => "Black",
+            };
+            // The tags holding the names are named after the colors
+            let name = self.pgn.tag(label).unwrap_or("?");
+            let text = format!("{} ({})", name.chars().take(32).collect::<String>(), label);
+            let text = graphics::Text::new(text)
+                .set_layout(TextLayout {
+                    h_align: graphics::TextAlign::Begin,
+                    v_align: graphics::TextAlign::Middle,
+                })
+                .set_scale(20.0)
+                .clone();
+            canvas.draw(&text, vec2(GRID_X, y));
+        }
+
+        let played = self.history.moves.len();
+        let status = match played == self.moves.len() {
+            true => format!(
+                "End of the game, result {}",
+                self.pgn.tag("Result").unwrap_or("*")
+            ),
+            false => format!(
+                "Move {} of {}, step with the arrow keys",
+                played,
+                self.moves.len()
+            ),
+        };
+        let status = graphics::Text::new(status)
+            .set_layout(TextLayout::center())
+            .set_scale(20.0)
+            .clone();
+        canvas.draw(
+            &status,
+            vec2(GRID_X + TILE_SIZE * 4.0, GRID_Y * 1.5 + TILE_SIZE * 8.0),
+        );
+
+        canvas.draw(&self.drawables.move_list_background, vec2(0.0, 0.0));
+        let current = self.current_row();
+        // Keep the current move in the middle of the list where possible
+        let last_page = self.rows.len().saturating_sub(MOVE_LIST_ROWS);
+        let top = current
+            .map_or(0, |row| row.saturating_sub(MOVE_LIST_ROWS / 2))
+            .min(last_page);
+        draw_move_list(&mut canvas, &self.rows, top, current);
+
+        if self.show_fen {
+            draw_fen(&mut canvas, &self.drawables, &self.history.fen(&self.game));
+        }
+
+        canvas.finish(ctx)
+    }
+
+    fn key_down_event(
+        &mut self,
+        ctx: &mut Context,
+        input: KeyInput,
+        _repeated: bool,
+    ) -> Result<(), ggez::GameError> {
+        match input.keycode {
+            Some(KeyCode::Escape) => ctx.request_quit(),
+            Some(KeyCode::Right) => {
+                self.forward();
+            }
+            Some(KeyCode::Left) => self.back(1),
+            Some(KeyCode::Home) | Some(KeyCode::Up) => self.back(self.moves.len()),
+            Some(KeyCode::End) | Some(KeyCode::Down) => while self.forward() {},
+            Some(KeyCode::F) => self.toggle_fen(),
+            Some(KeyCode::B) => self.flipped = !self.flipped,
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+use chess_lib::board::pieces::{get_legal_moves, move_piece, Color, Move, PieceType};
+use chess_lib::board::Board;
+use chess_lib::game::Game;
+use chess_networking::PromotionPiece;
+use std::fmt;
+
+use crate::fen::{self, Fen};
+use crate::grid::GridPosition;
+use crate::san;
+
+/// Terminal states that follow from the position on the board
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ending {
+    /// The given color is checkmated
+    Checkmate(Color),
+    Stalemate,
+    ThreefoldRepetition,
+    /// Fifty moves by each side without a capture or pawn move
+    FiftyMoveRule,
+    /// Neither side has the material left to deliver checkmate
+    InsufficientMaterial,
+}
+
+impl Ending {
+    pub fn is_draw(&self) -> bool {
+        !matches!(self, Ending::Checkmate(_))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CastlingRights {
+    pub white_kingside: bool,
+    pub white_queenside: bool,
+    pub black_kingside: bool,
+    pub black_queenside: bool,
+}
+
+impl CastlingRights {
+    /// Parses the castling field of a FEN, e.g. "KQkq" or "-"
+    pub fn parse(field: &str) -> Option<CastlingRights> {
+        let mut rights = CastlingRights {
+            white_kingside: false,
+            white_queenside: false,
+            black_kingside: false,
+            black_queenside: false,
+        };
+        if field == "-" {
+            return Some(rights);
+        }
+        for c in field.chars() {
+            let right = match c {
+                'K' => &mut rights.white_kingside,
+                'Q' => &mut rights.white_queenside,
+                'k' => &mut rights.black_kingside,
+                'q' => &mut rights.black_queenside,
+                _ => return None,
+            };
+            if *right {
+                return None;
+            }
+            *right = true;
+        }
+        Some(rights)
+    }
+
+    /// Whether `color` may still castle towards the rook on file `rook_x`
+    pub fn allows(&self, color: Color, rook_x: i32) -> bool {
+        match (color, rook_x) {
+            (Color::WHITE, 7) => self.white_kingside,
+            (Color::WHITE, 0) => self.white_queenside,
+            (Color::BLACK, 7) => self.black_kingside,
+            (Color::BLACK, 0) => self.black_queenside,
+            _ => false,
+        }
+    }
+
+    /// Removes the rights that depend on a king or rook that stood on (x, y)
+    pub fn clear_square(&mut self, x: usize, y: usize) {
+        match (x, y) {
+            (4, 7) => (self.white_kingside, self.white_queenside) = (false, false),
+            (4, 0) => (self.black_kingside, self.black_queenside) = (false, false),
+            (7, 7) => self.white_kingside = false,
+            (0, 7) => self.white_queenside = false,
+            (7, 0) => self.black_kingside = false,
+            (0, 0) => self.black_queenside = false,
+            _ => {}
+        }
+    }
+}
+
+/// Writes the rights as in the castling field of a FEN
+impl fmt::Display for CastlingRights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rights = [
+            (self.white_kingside, 'K'),
+            (self.white_queenside, 'Q'),
+            (self.black_kingside, 'k'),
+            (self.black_queenside, 'q'),
+        ];
+        let field: String = rights
+            .iter()
+            .filter(|(allowed, _)| *allowed)
+            .map(|(_, c)| c)
+            .collect();
+        match field.is_empty() {
+            true => write!(f, "-"),
+            false => write!(f, "{}", field),
+        }
+    }
+}
+
+/// A move as it was played, kept so games can be replayed
+#[derive(Clone, Copy, PartialEq)]
+pub struct PlayedMove {
+    pub from: GridPosition,
+    pub to: Move,
+    pub promotion: Option<PromotionPiece>,
+}
+
+/// Position state chess_lib does not keep: the moves played from the starting
+/// position, earlier positions, castling rights, the en passant square and the move
+/// counters
+pub struct PositionHistory {
+    pub start: Fen,
+    pub moves: Vec<PlayedMove>,
+    /// The moves in standard algebraic notation
+    pub san: Vec<String>,
+    positions: Vec<String>,
+    pub castling: CastlingRights,
+    /// Square a pawn skipped over with a double push on the last move
+    pub en_passant: Option<Move>,
+    /// Halfmoves since the last capture or pawn move
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+impl PositionHistory {
+    pub fn new(game: &Game, start: &Fen) -> PositionHistory {
+        let mut history = PositionHistory {
+            start: start.clone(),
+            moves: Vec::new(),
+            san: Vec::new(),
+            positions: Vec::new(),
+            castling: start.castling,
+            en_passant: start.en_passant,
+            halfmove_clock: start.halfmove_clock,
+            fullmove_number: start.fullmove_number,
+        };
+        history.positions.push(history.position_key(game));
+        history
+    }
+
+    /// Records the position after `moved` went from `from` to `to`, written as `san`
+    pub fn record(
+        &mut self,
+        game: &Game,
+        played: PlayedMove,
+        san: String,
+        moved: PieceType,
+        captured: bool,
+    ) {
+        let PlayedMove { from, to, .. } = played;
+        self.moves.push(played);
+        self.san.push(san);
+        // Captures and pawn moves can never be repeated past
+        if moved == PieceType::PAWN || captured {
+            self.halfmove_clock = 0;
+            self.positions.clear();
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if game.turn == Color::WHITE {
+            self.fullmove_number += 1;
+        }
+
+        self.castling.clear_square(from.x, from.y);
+        self.castling.clear_square(to.0 as usize, to.1 as usize);
+        self.en_passant = match moved == PieceType::PAWN && (to.1 - from.y as i32).abs() == 2 {
+            true => Some(Move(to.0, (to.1 + from.y as i32) / 2)),
+            false => None,
+        };
+
+        self.positions.push(self.position_key(game));
+    }
+
+    /// The current position, with the board and side to move taken from `game`
+    pub fn fen(&self, game: &Game) -> Fen {
+        Fen {
+            placement: fen::placement(&game.board),
+            turn: game.turn,
+            castling: self.castling,
+            en_passant: self.en_passant,
+            halfmove_clock: self.halfmove_clock,
+            fullmove_number: self.fullmove_number,
+        }
+    }
+
+    /// Identifies a position for repetition purposes
+    fn position_key(&self, game: &Game) -> String {
+        // The en passant square only tells positions apart while a pawn can take there
+        let en_passant = self
+            .en_passant
+            .filter(|_| can_capture_en_passant(&game.board, game.turn, self.en_passant));
+        format!(
+            "{} {:?} {:?} {:?}",
+            fen::placement(&game.board),
+            game.turn,
+            self.castling,
+            en_passant
+        )
+    }
+
+    /// How many times the current position has occurred
+    pub fn repetitions(&self) -> usize {
+        match self.positions.last() {
+            Some(current) => self.positions.iter().filter(|p| *p == current).count(),
+            None => 0,
+        }
+    }
+}
+
+pub fn opponent(color: Color) -> Color {
+    match color {
+        Color::WHITE => Color::BLACK,
+        Color::BLACK => Color::WHITE,
+        Color::EMPTY => Color::EMPTY,
+    }
+}
+
+/// Whether the square (x, y) is attacked by any piece of color `by`
+pub fn is_attacked(board: &Board, x: i32, y: i32, by: Color) -> bool {
+    let piece_at = |x: i32, y: i32| {
+        if !(0..8).contains(&x) || !(0..8).contains(&y) {
+            return None;
+        }
+        let piece = board.pieces[y as usize][x as usize];
+        match piece.piece_type {
+            PieceType::EMPTY => None,
+            _ => Some((piece.piece_type, piece.color)),
+        }
+    };
+
+    // White pawns move towards y = 0, so they attack from the row below
+    let pawn_dy = if by == Color::WHITE { 1 } else { -1 };
+    if [-1, 1]
+        .iter()
+        .any(|dx| piece_at(x + dx, y + pawn_dy) == Some((PieceType::PAWN, by)))
+    {
+        return true;
+    }
+
+    let knight_jumps = [
+        (1, 2),
+        (2, 1),
+        (2, -1),
+        (1, -2),
+        (-1, -2),
+        (-2, -1),
+        (-2, 1),
+        (-1, 2),
+    ];
+    if knight_jumps
+        .iter()
+        .any(|(dx, dy)| piece_at(x + dx, y + dy) == Some((PieceType::KNIGHT, by)))
+    {
+        return true;
+    }
+
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            if (dx, dy) == (0, 0) {
+                continue;
+            }
+            if piece_at(x + dx, y + dy) == Some((PieceType::KING, by)) {
+                return true;
+            }
+
+            // Slide until the first piece in this direction
+            let diagonal = dx != 0 && dy != 0;
+            let (mut cx, mut cy) = (x + dx, y + dy);
+            while (0..8).contains(&cx) && (0..8).contains(&cy) {
+                if let Some((piece_type, color)) = piece_at(cx, cy) {
+                    let slides = match piece_type {
+                        PieceType::QUEEN => true,
+                        PieceType::BISHOP => diagonal,
+                        PieceType::ROOK => !diagonal,
+                        _ => false,
+                    };
+                    if slides && color == by {
+                        return true;
+                    }
+                    break;
+                }
+                cx += dx;
+                cy += dy;
+            }
+        }
+    }
+    false
+}
+
+pub fn find_king(board: &Board, color: Color) -> Option<(i32, i32)> {
+    (0..8)
+        .flat_map(|y| (0..8).map(move |x| (x, y)))
+        .find(|&(x, y)| {
+            let piece = board.pieces[y as usize][x as usize];
+            piece.piece_type == PieceType::KING && piece.color == color
+        })
+}
+
+pub fn in_check(board: &Board, color: Color) -> bool {
+    match find_king(board, color) {
+        Some((x, y)) => is_attacked(board, x, y, opponent(color)),
+        None => false,
+    }
+}
+
+/// Whether `color` has any legal move, counting en passant captures over
+/// `en_passant`
+pub fn has_legal_moves(board: &Board, color: Color, en_passant: Option<Move>) -> bool {
+    can_capture_en_passant(board, color, en_passant)
+        || (0..8).any(|y| {
+            (0..8).any(|x| {
+                let piece = board.pieces[y as usize][x as usize];
+                piece.piece_type != PieceType::EMPTY
+                    && piece.color == color
+                    && !get_legal_moves(*board, x, y, color).is_empty()
+            })
+        })
+}
+
+/// En passant capture open to the pawn on (x, y) when a pawn skipped over
+/// `en_passant`, unless it would leave the capturing side in check. chess_lib only
+/// sees the board, so it never offers these.
+pub fn en_passant_captures(board: &Board, en_passant: Option<Move>, x: i32, y: i32) -> Vec<Move> {
+    let Some(square) = en_passant else {
+        return Vec::new();
+    };
+    let piece = board.pieces[y as usize][x as usize];
+    // White pawns move towards y = 0
+    let dy = if piece.color == Color::WHITE { -1 } else { 1 };
+    let captured = board.pieces[y as usize][square.0 as usize];
+    if piece.piece_type != PieceType::PAWN
+        || square.1 != y + dy
+        || (square.0 - x).abs() != 1
+        || captured.piece_type != PieceType::PAWN
+        || captured.color != opponent(piece.color)
+    {
+        return Vec::new();
+    }
+    let mut after = *board;
+    after.pieces[square.1 as usize][square.0 as usize] = piece;
+    clear(&mut after, x, y);
+    clear(&mut after, square.0, y);
+    match in_check(&after, piece.color) {
+        true => Vec::new(),
+        false => vec![square],
+    }
+}
+
+/// Whether any pawn of `color` can capture en passant
+pub fn can_capture_en_passant(board: &Board, color: Color, en_passant: Option<Move>) -> bool {
+    (0..8).any(|y| {
+        (0..8).any(|x| {
+            board.pieces[y as usize][x as usize].color == color
+                && !en_passant_captures(board, en_passant, x, y).is_empty()
+        })
+    })
+}
+
+/// Empties the square (x, y)
+pub fn clear(board: &mut Board, x: i32, y: i32) {
+    let square = &mut board.pieces[y as usize][x as usize];
+    square.piece_type = PieceType::EMPTY;
+    square.color = Color::EMPTY;
+}
+
+/// Applies a move that has been checked to be legal and records it in the history.
+/// Returns whether chess_lib accepted the move.
+pub fn play_move(
+    game: &mut Game,
+    history: &mut PositionHistory,
+    from: GridPosition,
+    to: Move,
+    promotion: Option<PromotionPiece>,
+) -> bool {
+    let played = PlayedMove {
+        from,
+        to,
+        promotion,
+    };
+    let piece = game.board.pieces[from.y][from.x];
+    let target = game.board.pieces[to.1 as usize][to.0 as usize];
+    let en_passant = piece.piece_type == PieceType::PAWN
+        && target.piece_type == PieceType::EMPTY
+        && Some(to) == history.en_passant
+        && to.0 != from.x as i32;
+    let captured = en_passant || target.piece_type != PieceType::EMPTY;
+    let san = san::describe(game, history, played);
+
+    let before = game.board;
+    if en_passant {
+        // chess_lib does not know en passant, so the captured pawn is moved onto the
+        // target square first and the move is played as a plain capture
+        game.board.pieces[to.1 as usize][to.0 as usize] = game.board.pieces[from.y][to.0 as usize];
+        clear(&mut game.board, to.0, from.y as i32);
+    }
+    if move_piece(to, from.x as i32, from.y as i32, game).is_err() {
+        game.board = before;
+        return false;
+    }
+    if let Some(promotion) = promotion {
+        crate::promote(game, to, promotion);
+    }
+    history.record(game, played, san, piece.piece_type, captured);
+    // The suffix depends on en passant captures the move just opened up
+    let suffix = san::check_suffix(game, history.en_passant);
+    if let Some(san) = history.san.last_mut() {
+        san.push_str(suffix);
+    }
+    true
+}
+
+/// Takes back the last `moves` halfmoves by replaying the game from its starting
+/// position. Returns false, leaving the game alone, if fewer moves were played.
+pub fn take_back(game: &mut Game, history: &mut PositionHistory, moves: usize) -> bool {
+    if moves > history.moves.len() {
+        return false;
+    }
+    let kept = history.moves.len() - moves;
+    let replayed: Vec<PlayedMove> = history.moves[..kept].to_vec();
+    let start = history.start.clone();
+    *game = start.to_game();
+    *history = PositionHistory::new(game, &start);
+    for played in replayed {
+        play_move(game, history, played.from, played.to, played.promotion);
+    }
+    true
+}
+
+/// Legal moves for the piece on (x, y), including en passant captures and leaving
+/// out castling moves the castling rights no longer allow
+pub fn legal_moves(game: &Game, history: &PositionHistory, x: i32, y: i32) -> Vec<Move> {
+    let piece = game.board.pieces[y as usize][x as usize];
+    let mut moves = get_legal_moves(game.board, x, y, piece.color);
+    for capture in en_passant_captures(&game.board, history.en_passant, x, y) {
+        if !moves.contains(&capture) {
+            moves.push(capture);
+        }
+    }
+    if piece.piece_type == PieceType::KING {
+        moves.retain(|mov| {
+            let castling = (mov.0 - x).abs() == 2;
+            !castling
+                || history
+                    .castling
+                    .allows(piece.color, if mov.0 > x { 7 } else { 0 })
+        });
+    }
+    moves
+}
+
+/// Whether no sequence of legal moves can lead to checkmate: bare kings, a single
+/// minor piece, or only bishops all standing on the same square color
+pub fn insufficient_material(board: &Board) -> bool {
+    let mut minors = Vec::new();
+    for (y, row) in board.pieces.iter().enumerate() {
+        for (x, piece) in row.iter().enumerate() {
+            match piece.piece_type {
+                PieceType::EMPTY | PieceType::KING => {}
+                PieceType::BISHOP | PieceType::KNIGHT => minors.push((piece.piece_type, x + y)),
+                _ => return false,
+            }
+        }
+    }
+    match minors.as_slice() {
+        [] | [_] => true,
+        [(_, first), ..] => minors.iter().all(|&(piece_type, square)| {
+            piece_type == PieceType::BISHOP && square % 2 == first % 2
+        }),
+    }
+}
+
+/// Checks whether the game has ended by the position on the board or its history
+pub fn ending(game: &Game, history: &PositionHistory) -> Option<Ending> {
+    // chess_lib's mate flags are set inside move_piece, before a promotion replaces
+    // the queen it assumes, so mate is worked out from the final board instead
+    if !has_legal_moves(&game.board, game.turn, history.en_passant) {
+        return match in_check(&game.board, game.turn) {
+            true => Some(Ending::Checkmate(game.turn)),
+            false => Some(Ending::Stalemate),
+        };
+    }
+    if history.repetitions() >= 3 {
+        return Some(Ending::ThreefoldRepetition);
+    }
+    if history.halfmove_clock >= 100 {
+        return Some(Ending::FiftyMoveRule);
+    }
+    if insufficient_material(&game.board) {
+        return Some(Ending::InsufficientMaterial);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(fen: &str) -> (Game, PositionHistory) {
+        let start = Fen::parse(fen).unwrap();
+        let game = start.to_game();
+        let history = PositionHistory::new(&game, &start);
+        (game, history)
+    }
+
+    /// Plays moves given in SAN, which have to be legal
+    fn play(game: &mut Game, history: &mut PositionHistory, moves: &[&str]) {
+        for &san in moves {
+            let played = san::parse(game, history, san).unwrap_or_else(|| panic!("{}", san));
+            assert!(play_move(
+                game,
+                history,
+                played.from,
+                played.to,
+                played.promotion
+            ));
+        }
+    }
+
+    #[test]
+    fn threefold_repetition() {
+        let (mut game, mut history) = setup(fen::STARTING_FEN);
+        let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+        play(&mut game, &mut history, &shuffle);
+        assert_eq!(history.repetitions(), 2);
+        assert_eq!(ending(&game, &history), None);
+        play(&mut game, &mut history, &shuffle);
+        assert_eq!(history.repetitions(), 3);
+        assert_eq!(ending(&game, &history), Some(Ending::ThreefoldRepetition));
+    }
+
+    #[test]
+    fn castling_rights_tell_positions_apart() {
+        let (mut game, mut history) = setup("r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R w KQkq - 0 1");
+        // The rooks come back, but castling on that side is gone for good
+        let shuffle = ["Rb1", "Rb8", "Ra1", "Ra8"];
+        play(&mut game, &mut history, &shuffle);
+        play(&mut game, &mut history, &shuffle);
+        assert_eq!(history.repetitions(), 2);
+    }
+
+    #[test]
+    fn pawn_moves_reset_repetitions() {
+        let (mut game, mut history) = setup(fen::STARTING_FEN);
+        play(&mut game, &mut history, &["Nf3", "Nf6", "Ng1", "Ng8", "e4"]);
+        assert_eq!(history.repetitions(), 1);
+        assert_eq!(history.halfmove_clock, 0);
+    }
+
+    #[test]
+    fn unusable_en_passant_square_does_not_count() {
+        // No black pawn can take on e3, so the position after the knights return
+        // repeats the one after e4
+        let (mut game, mut history) = setup(fen::STARTING_FEN);
+        play(&mut game, &mut history, &["e4", "Nf6", "Nf3", "Ng8", "Ng1"]);
+        assert_eq!(history.repetitions(), 2);
+    }
+
+    #[test]
+    fn fifty_move_rule() {
+        let (mut game, mut history) = setup("4k3/8/8/8/8/8/8/R3K3 w - - 98 70");
+        play(&mut game, &mut history, &["Ra2"]);
+        assert_eq!(ending(&game, &history), None);
+        play(&mut game, &mut history, &["Kd7"]);
+        assert_eq!(history.halfmove_clock, 100);
+        assert_eq!(ending(&game, &history), Some(Ending::FiftyMoveRule));
+    }
+
+    #[test]
+    fn captures_reset_the_fifty_move_count() {
+        let (mut game, mut history) = setup("4k3/8/8/8/8/8/r7/R3K3 w - - 99 70");
+        play(&mut game, &mut history, &["Rxa2"]);
+        assert_eq!(history.halfmove_clock, 0);
+        assert_eq!(ending(&game, &history), None);
+    }
+}
@@ -0,0 +1,275 @@
+use chess_lib::board::pieces::{Color, Move, PieceType};
+use chess_lib::game::Game;
+use chess_networking::PromotionPiece;
+
+use crate::fen::{self, Fen};
+use crate::rules::{self, PlayedMove, PositionHistory};
+
+/// Standard algebraic notation of a move about to be played, such as "Nbxd2" or
+/// "e8=Q", without the check suffix that depends on the position afterwards
+pub fn describe(game: &Game, history: &PositionHistory, played: PlayedMove) -> String {
+    let PlayedMove {
+        from,
+        to,
+        promotion,
+    } = played;
+    let piece = game.board.pieces[from.y][from.x];
+    let target = game.board.pieces[to.1 as usize][to.0 as usize];
+    let destination = fen::square_name(to);
+
+    if piece.piece_type == PieceType::KING && (to.0 - from.x as i32).abs() == 2 {
+        return match to.0 > from.x as i32 {
+            true => "O-O".to_string(),
+            false => "O-O-O".to_string(),
+        };
+    }
+
+    if piece.piece_type == PieceType::PAWN {
+        // Pawns only change file when capturing, en passant included
+        let mut san = match to.0 != from.x as i32 {
+            true => format!("{}x{}", file_name(from.x), destination),
+            false => destination,
+        };
+        if let Some(promotion) = promotion {
+            san.push('=');
+            san.push(promotion_letter(promotion));
+        }
+        return san;
+    }
+
+    // Name the origin file, rank or both if another such piece could go there too
+    let rivals: Vec<(usize, usize)> = (0..8)
+        .flat_map(|y| (0..8).map(move |x| (x, y)))
+        .filter(|&(x, y)| {
+            let other = game.board.pieces[y][x];
+            (x, y) != (from.x, from.y)
+                && other.piece_type == piece.piece_type
+                && other.color == piece.color
+                && rules::legal_moves(game, history, x as i32, y as i32).contains(&to)
+        })
+        .collect();
+    let shares_file = rivals.iter().any(|&(x, _)| x == from.x);
+    let shares_rank = rivals.iter().any(|&(_, y)| y == from.y);
+    let disambiguation = match (rivals.is_empty(), shares_file, shares_rank) {
+        (true, _, _) => String::new(),
+        (false, false, _) => file_name(from.x).to_string(),
+        (false, true, false) => rank_name(from.y).to_string(),
+        (false, true, true) => format!("{}{}", file_name(from.x), rank_name(from.y)),
+    };
+
+    let capture = match target.piece_type {
+        PieceType::EMPTY => "",
+        _ => "x",
+    };
+    format!(
+        "{}{}{}{}",
+        piece_letter(piece.piece_type),
+        disambiguation,
+        capture,
+        destination
+    )
+}
+
+/// "#" if the side to move is checkmated, "+" if it is in check. `en_passant` is
+/// the square the move just played skipped over, if any.
+pub fn check_suffix(game: &Game, en_passant: Option<Move>) -> &'static str {
+    if !rules::in_check(&game.board, game.turn) {
+        ""
+    } else if rules::has_legal_moves(&game.board, game.turn, en_passant) {
+        "+"
+    } else {
+        "#"
+    }
+}
+
+/// Finds the move written as `san` in the current position. Check marks and
+/// annotations such as "!?" are ignored, and castling may be written with zeros.
+pub fn parse(game: &Game, history: &PositionHistory, san: &str) -> Option<PlayedMove> {
+    let wanted = normalize(san);
+    for y in 0..8 {
+        for x in 0..8 {
+            let piece = game.board.pieces[y][x];
+            if piece.piece_type == PieceType::EMPTY || piece.color != game.turn {
+                continue;
+            }
+            for to in rules::legal_moves(game, history, x as i32, y as i32) {
+                let promotions = match crate::is_promotion(piece.piece_type, to) {
+                    true => crate::PROMOTION_CHOICES.map(Some).to_vec(),
+                    false => vec![None],
+                };
+                for promotion in promotions {
+                    let played = PlayedMove {
+                        from: (x, y).into(),
+                        to,
+                        promotion,
+                    };
+                    if normalize(&describe(game, history, played)) == wanted {
+                        return Some(played);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Strips what `parse` ignores, along with the "=" some write promotions without
+fn normalize(san: &str) -> String {
+    san.trim_end_matches(['+', '#', '!', '?'])
+        .replace('0', "O")
+        .replace('=', "")
+}
+
+/// Groups moves into numbered pairs such as "12. Nf3 Nc6", starting from the move
+/// number and side to move of `start`
+pub fn numbered(start: &Fen, moves: &[String]) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut number = start.fullmove_number;
+    let mut moves = moves.iter();
+    if start.turn == Color::BLACK {
+        if let Some(first) = moves.next() {
+            rows.push(format!("{}... {}", number, first));
+            number += 1;
+        }
+    }
+    let moves: Vec<&String> = moves.collect();
+    for pair in moves.chunks(2) {
+        match pair {
+            [white, black] => rows.push(format!("{}. {} {}", number, white, black)),
+            [white] => rows.push(format!("{}. {}", number, white)),
+            _ => unreachable!(),
+        }
+        number += 1;
+    }
+    rows
+}
+
+fn piece_letter(piece_type: PieceType) -> &'static str {
+    match piece_type {
+        PieceType::KNIGHT => "N",
+        PieceType::BISHOP => "B",
+        PieceType::ROOK => "R",
+        PieceType::QUEEN => "Q",
+        PieceType::KING => "K",
+        PieceType::PAWN | PieceType::EMPTY => "",
+    }
+}
+
+fn promotion_letter(promotion: PromotionPiece) -> char {
+    match promotion {
+        PromotionPiece::Queen => 'Q',
+        PromotionPiece::Rook => 'R',
+        PromotionPiece::Bishop => 'B',
+        PromotionPiece::Knight => 'N',
+    }
+}
+
+fn file_name(x: usize) -> char {
+    (b'a' + x as u8) as char
+}
+
+fn rank_name(y: usize) -> char {
+    (b'8' - y as u8) as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(fen: &str) -> (Game, PositionHistory) {
+        let start = Fen::parse(fen).unwrap();
+        let game = start.to_game();
+        let history = PositionHistory::new(&game, &start);
+        (game, history)
+    }
+
+    /// Plays moves given in SAN and gives them back as recorded
+    fn play(fen: &str, moves: &[&str]) -> (Game, PositionHistory) {
+        let (mut game, mut history) = setup(fen);
+        for &san in moves {
+            let played = parse(&game, &history, san).unwrap_or_else(|| panic!("{}", san));
+            assert!(rules::play_move(
+                &mut game,
+                &mut history,
+                played.from,
+                played.to,
+                played.promotion
+            ));
+        }
+        (game, history)
+    }
+
+    #[test]
+    fn records_checks_and_mate() {
+        let (_, history) = play(
+            fen::STARTING_FEN,
+            &["e4", "e5", "Qh5", "Nc6", "Bc4", "Nf6", "Qxf7"],
+        );
+        assert_eq!(
+            history.san,
+            ["e4", "e5", "Qh5", "Nc6", "Bc4", "Nf6", "Qxf7#"]
+        );
+    }
+
+    #[test]
+    fn disambiguates_by_file_then_rank() {
+        let (game, history) = setup("4k3/8/8/R7/8/8/8/RN2KN2 w - - 0 1");
+        let knight = parse(&game, &history, "Nbd2").unwrap();
+        assert_eq!((knight.from.x, knight.from.y), (1, 7));
+        assert_eq!(describe(&game, &history, knight), "Nbd2");
+        let rook = parse(&game, &history, "R1a3").unwrap();
+        assert_eq!((rook.from.x, rook.from.y), (0, 7));
+        assert_eq!(describe(&game, &history, rook), "R1a3");
+        assert!(parse(&game, &history, "Nd2").is_none());
+    }
+
+    #[test]
+    fn promotions() {
+        let (game, history) = setup("4k3/P7/8/8/8/8/8/4K3 w - - 0 1");
+        let played = parse(&game, &history, "a8=N").unwrap();
+        assert_eq!(played.promotion, Some(PromotionPiece::Knight));
+        assert_eq!(describe(&game, &history, played), "a8=N");
+        assert_eq!(
+            parse(&game, &history, "a8Q").unwrap().promotion,
+            Some(PromotionPiece::Queen)
+        );
+        let (_, history) = play("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", &["a8=Q"]);
+        assert_eq!(history.san, ["a8=Q+"]);
+    }
+
+    #[test]
+    fn castling_and_annotations() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        let (game, history) = setup(fen);
+        let castle = parse(&game, &history, "0-0!?").unwrap();
+        assert_eq!((castle.from.x, castle.to), (4, Move(6, 7)));
+        let (_, history) = play(fen, &["O-O-O", "O-O"]);
+        assert_eq!(history.san, ["O-O-O", "O-O"]);
+    }
+
+    #[test]
+    fn en_passant_from_a_starting_fen() {
+        let (game, history) = play("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2", &["exd6"]);
+        assert_eq!(history.san, ["exd6"]);
+        assert_eq!(fen::placement(&game.board), "4k3/8/3P4/8/8/8/8/4K3");
+    }
+
+    #[test]
+    fn rejects_illegal_moves() {
+        let (game, history) = setup(fen::STARTING_FEN);
+        for san in ["Ke2", "e5", "Nd2", "O-O", "xyz"] {
+            assert!(parse(&game, &history, san).is_none(), "{}", san);
+        }
+    }
+
+    #[test]
+    fn numbers_moves() {
+        let start = Fen::parse(fen::STARTING_FEN).unwrap();
+        let moves = ["e4", "e5", "Nf3"].map(String::from);
+        assert_eq!(numbered(&start, &moves), ["1. e4 e5", "2. Nf3"]);
+
+        let start = Fen::parse("4k3/8/8/8/8/8/8/4K3 b - - 0 12").unwrap();
+        let moves = ["Kd7", "Kd2", "Kc6"].map(String::from);
+        assert_eq!(numbered(&start, &moves), ["12... Kd7", "13. Kd2 Kc6"]);
+    }
+}
@@ -0,0 +1,109 @@
+use chess_lib::board::pieces::Color;
+use ggez::{GameError, GameResult};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::fen::Fen;
+use crate::pgn::Pgn;
+use crate::replay;
+use crate::rules::PlayedMove;
+
+// Tags a save has on top of the PGN export
+const LOCAL_COLOR_TAG: &str = "LocalColor";
+const TIME_LEFT_TAGS: [(&str, Color); 2] = [
+    ("WhiteTimeLeft", Color::WHITE),
+    ("BlackTimeLeft", Color::BLACK),
+];
+
+/// A networked game read back from the save one of its players wrote. Saves are the
+/// game's PGN with extra tags for the color played from that side and the time left
+/// on both clocks.
+pub struct SavedGame {
+    pub local_color: Color,
+    pub local_name: String,
+    pub clock: Option<Clock>,
+    pub start: Fen,
+    pub moves: Vec<PlayedMove>,
+}
+
+/// Writes `pgn` to `path` as the save of the player of `local_color`
+pub fn write(
+    path: &Path,
+    mut pgn: Pgn,
+    local_color: Color,
+    clock: Option<&Clock>,
+) -> io::Result<()> {
+    pgn.tags.push((
+        LOCAL_COLOR_TAG.to_string(),
+        color_name(local_color).to_string(),
+    ));
+    if let Some(clock) = clock {
+        for (tag, color) in TIME_LEFT_TAGS {
+            let seconds = clock.remaining(color).as_secs_f64();
+            pgn.tags.push((tag.to_string(), format!("{:.3}", seconds)));
+        }
+    }
+    pgn.save(path)
+}
+
+/// Reads a save and replays its moves, refusing games that have already ended
+pub fn load(path: &Path) -> GameResult<SavedGame> {
+    let (pgn, _, history) = replay::load(path)?;
+    let invalid = |problem: &str| {
+        GameError::CustomError(format!("Invalid save {}: {}", path.display(), problem))
+    };
+    if pgn.tag("Result") != Some("*") {
+        return Err(GameError::CustomError(format!(
+            "The game saved in {} has already ended",
+            path.display()
+        )));
+    }
+
+    let local_color = match pgn.tag(LOCAL_COLOR_TAG) {
+        Some("White") => Color::WHITE,
+        Some("Black") => Color::BLACK,
+        _ => return Err(invalid("no LocalColor tag")),
+    };
+
+    let clock = match pgn.tag("TimeControl") {
+        None | Some("-") => None,
+        Some(time_control) => {
+            let (time, inc) = time_control.split_once('+').unwrap_or((time_control, "0"));
+            let seconds = |value: &str| {
+                value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| invalid("bad TimeControl tag"))
+            };
+            let mut clock = Clock::new(seconds(time)?, seconds(inc)?);
+            for (tag, color) in TIME_LEFT_TAGS {
+                let remaining = pgn
+                    .tag(tag)
+                    .and_then(|value| value.parse().ok())
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| invalid(&format!("bad or missing {} tag", tag)))?;
+                clock.set_remaining(color, remaining);
+            }
+            Some(clock)
+        }
+    };
+
+    Ok(SavedGame {
+        local_color,
+        local_name: pgn.tag(color_name(local_color)).unwrap_or("?").to_string(),
+        clock,
+        start: history.start,
+        moves: history.moves,
+    })
+}
+
+/// Color as written in the tags, which is also the name of the tag holding the name
+/// of its player
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::WHITE => "White",
+        _ => "Black",
+    }
+}
@@ -0,0 +1,285 @@
+use chess_lib::board::pieces::{Color, Move};
+use chess_networking::Ack;
+use ggez::{GameError, GameResult};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fen::Fen;
+use crate::networking::{Message, ReadError, Takeback};
+use crate::opponent::{self, GameCopy, Opponent};
+use crate::rules;
+
+// How long the engine gets to answer "uci" and "isready"
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the engine gets to exit after "quit" before it is killed
+const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Settings passed on to the engine
+pub struct EngineSettings {
+    /// Value of the "Skill Level" option, left at the engine's default when None
+    pub skill_level: Option<u32>,
+    /// Value of the "Threads" option, left at the engine's default when None
+    pub threads: Option<u32>,
+    /// Thinking time per move
+    pub movetime: Duration,
+}
+
+/// Opponent backed by a UCI engine running as a child process. The engine is given
+/// the starting position and every move since, and its "bestmove" answers are played
+/// from a copy of the game. Draw offers are declined.
+pub struct UciEngine {
+    color: Color,
+    name: String,
+    copy: GameCopy,
+    start: String,
+    // Moves played since the start, in the engine's coordinate notation
+    moves: Vec<String>,
+    movetime: Duration,
+    child: Child,
+    stdin: ChildStdin,
+    // Lines the engine printed, read on a background thread
+    lines: Receiver<String>,
+    // Whether a search is running, and whether its result is for a position that was
+    // taken back
+    thinking: bool,
+    stale_search: bool,
+    // Packets waiting to be picked up by poll
+    outbox: VecDeque<Message>,
+}
+
+impl UciEngine {
+    /// Starts the engine at `path` and sets it up to play `color` from `start`
+    pub fn launch(
+        path: &Path,
+        settings: &EngineSettings,
+        color: Color,
+        start: &Fen,
+    ) -> GameResult<UciEngine> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                GameError::CustomError(format!("Could not start engine {}: {}", path.display(), e))
+            })?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut engine = UciEngine {
+            color,
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "Engine".to_string()),
+            copy: GameCopy::new(start),
+            start: start.to_string(),
+            moves: Vec::new(),
+            movetime: settings.movetime,
+            child,
+            stdin,
+            lines,
+            thinking: false,
+            stale_search: false,
+            outbox: VecDeque::new(),
+        };
+
+        engine.send("uci")?;
+        for line in engine.wait_for("uciok")? {
+            if let Some(name) = line.strip_prefix("id name ") {
+                engine.name = name.trim().to_string();
+            }
+        }
+        if let Some(skill_level) = settings.skill_level {
+            engine.send(&format!("setoption name Skill Level value {}", skill_level))?;
+        }
+        if let Some(threads) = settings.threads {
+            engine.send(&format!("setoption name Threads value {}", threads))?;
+        }
+        engine.send("ucinewgame")?;
+        engine.send("isready")?;
+        engine.wait_for("readyok")?;
+
+        if engine.copy.game.turn == color {
+            engine.go()?;
+        }
+        Ok(engine)
+    }
+
+    fn send(&mut self, command: &str) -> GameResult {
+        println!("To engine: {}", command);
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    /// Blocks until the engine prints `reply`, returning the lines before it
+    fn wait_for(&mut self, reply: &str) -> GameResult<Vec<String>> {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        let mut lines = Vec::new();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(timeout) {
+                Ok(line) if line.trim() == reply => return Ok(lines),
+                Ok(line) => lines.push(line),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(GameError::CustomError(format!(
+                        "Engine did not answer with {}",
+                        reply
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(GameError::CustomError("Engine exited".to_string()))
+                }
+            }
+        }
+    }
+
+    /// Gives the engine the current position and lets it think
+    fn go(&mut self) -> GameResult {
+        let mut position = format!("position fen {}", self.start);
+        if !self.moves.is_empty() {
+            position += " moves ";
+            position += &self.moves.join(" ");
+        }
+        self.send(&position)?;
+        self.thinking = true;
+        self.send(&format!("go movetime {}", self.movetime.as_millis()))
+    }
+
+    /// Plays the engine's answer to a "bestmove" line, resigning on anything that is
+    /// not a move it can make
+    fn play(&mut self, bestmove: &str) {
+        let mov = bestmove
+            .split_whitespace()
+            .next()
+            .and_then(opponent::parse_coordinate_move)
+            .and_then(|mov| {
+                let from = (mov.from.0 as usize, 7 - mov.from.1 as usize).into(); // Adjust y
+                let to = Move(mov.to.0 as i32, 7 - mov.to.1 as i32); // Adjust y
+                self.copy.play(from, to, mov.promotion)
+            });
+        match mov {
+            Some(mov) => {
+                self.moves.push(opponent::coordinate_move(&mov));
+                self.outbox.push_back(Message::Move(mov));
+            }
+            None => {
+                println!("Engine resigns with bestmove {}", bestmove);
+                self.outbox.push_back(Message::Move(opponent::forfeit()));
+            }
+        }
+    }
+}
+
+impl Opponent for UciEngine {
+    fn local_color(&self) -> Option<Color> {
+        Some(rules::opponent(self.color))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn send_move(&mut self, mov: chess_networking::Move) -> GameResult {
+        if mov.forfeit {
+            self.outbox.push_back(Message::Ack(Ack {
+                ok: true,
+                end_state: None,
+            }));
+            return Ok(());
+        }
+        let ack = self.copy.ack(&mov);
+        if ack.ok {
+            self.moves.push(opponent::coordinate_move(&mov));
+            if ack.end_state.is_none() {
+                self.go()?;
+            }
+        }
+        self.outbox.push_back(Message::Ack(ack));
+        Ok(())
+    }
+
+    fn send_ack(&mut self, ack: Ack) -> GameResult {
+        if !ack.ok {
+            // The engine's copy of the game no longer matches the board
+            self.outbox.push_back(Message::Move(opponent::forfeit()));
+        }
+        Ok(())
+    }
+
+    fn send_takeback(&mut self, takeback: Takeback) -> GameResult {
+        let Takeback::Request { moves } = takeback else {
+            return Ok(());
+        };
+        if !self.copy.take_back(moves as usize) {
+            self.outbox.push_back(Message::Takeback(Takeback::Decline));
+            return Ok(());
+        }
+        self.moves.truncate(self.moves.len() - moves as usize);
+        if self.thinking {
+            // The engine still answers with a bestmove, which is thrown away
+            self.send("stop")?;
+            self.thinking = false;
+            self.stale_search = true;
+        }
+        self.outbox.push_back(Message::Takeback(Takeback::Accept));
+        if self.copy.game.turn == self.color {
+            self.go()?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Message>, ReadError> {
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => {
+                    if let Some(bestmove) = line.strip_prefix("bestmove ") {
+                        println!("From engine: {}", line);
+                        if std::mem::take(&mut self.stale_search) {
+                            continue;
+                        }
+                        self.thinking = false;
+                        self.play(bestmove);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if self.outbox.is_empty() {
+                        return Err(ReadError::Disconnected);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(self.outbox.pop_front())
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let deadline = Instant::now() + QUIT_TIMEOUT;
+        while let Ok(None) = self.child.try_wait() {
+            if Instant::now() >= deadline {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}